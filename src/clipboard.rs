@@ -0,0 +1,435 @@
+use std::fmt::Debug;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use tracing::{debug, trace};
+
+use crate::db::{ClipboardEntry, SerializableImage};
+
+// the kind of desktop session we run in, which decides what we read the
+// clipboard with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Session {
+    Wayland,
+    X11,
+    // macos, windows, or no graphical session at all
+    Other,
+}
+
+impl Session {
+    pub fn detect() -> Self {
+        Session::from_env(
+            std::env::var_os("WAYLAND_DISPLAY").is_some(),
+            std::env::var("XDG_SESSION_TYPE").ok().as_deref(),
+        )
+    }
+
+    // WAYLAND_DISPLAY is what wl-paste connects to, so it wins over the
+    // session type, e.g. in a nested compositor started from x11
+    fn from_env(wayland_display: bool, session_type: Option<&str>) -> Self {
+        match session_type {
+            _ if wayland_display => Session::Wayland,
+            Some("wayland") => Session::Wayland,
+            Some("x11") => Session::X11,
+            _ => Session::Other,
+        }
+    }
+}
+
+// something that can read and set the system clipboard
+pub trait ClipboardBackend: Send {
+    fn name(&self) -> &'static str;
+    // the current content, None if there is none, an error if there may be
+    // some that could not be read
+    fn read(&mut self) -> Result<Option<ClipboardEntry>, String>;
+    // mime is the type the entry was first copied as, if known
+    fn write(&mut self, entry: &ClipboardEntry, mime: Option<&str>) -> Result<(), String>;
+}
+
+// the cross platform clipboard. on some wayland compositors it reads stale
+// or empty content, and it cannot read html anywhere
+pub struct Arboard(arboard::Clipboard);
+
+impl Arboard {
+    pub fn open() -> Result<Self, String> {
+        arboard::Clipboard::new()
+            .map(Arboard)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl ClipboardBackend for Arboard {
+    fn name(&self) -> &'static str {
+        "arboard"
+    }
+
+    fn read(&mut self) -> Result<Option<ClipboardEntry>, String> {
+        let text_error = match self.0.get_text() {
+            Ok(text) => return Ok(Some(ClipboardEntry::Text(text))),
+            Err(e) => e,
+        };
+        trace!("arboard has no text: {}", text_error);
+        match (self.0.get_image(), text_error) {
+            (Ok(image), _) => Ok(Some(ClipboardEntry::Image(image.into()))),
+            (Err(arboard::Error::ContentNotAvailable), arboard::Error::ContentNotAvailable) => {
+                Ok(None)
+            }
+            (Err(arboard::Error::ContentNotAvailable), e) | (Err(e), _) => Err(e.to_string()),
+        }
+    }
+
+    fn write(&mut self, entry: &ClipboardEntry, _mime: Option<&str>) -> Result<(), String> {
+        let result = match entry.clone() {
+            ClipboardEntry::Text(t) => self.0.set_text(t),
+            // not every platform can hold html, plain text still works there
+            ClipboardEntry::Html { html, alt_text } => self
+                .0
+                .set_html(html, Some(alt_text.clone()))
+                .or_else(|_| self.0.set_text(alt_text)),
+            ClipboardEntry::Image(i) => self.0.set_image(i.into()),
+        };
+        result.map_err(|e| e.to_string())
+    }
+}
+
+// wl-paste and wl-copy from wl-clipboard, which see every type the wayland
+// clipboard offers. primary is the selection pasted with a middle click
+pub struct WlClipboard {
+    primary: bool,
+}
+
+impl ClipboardBackend for WlClipboard {
+    fn name(&self) -> &'static str {
+        "wl-clipboard"
+    }
+
+    fn read(&mut self) -> Result<Option<ClipboardEntry>, String> {
+        let primary = self.primary;
+        read_offered(&wayland_types(primary), |mime| {
+            output_of(
+                "wl-paste",
+                &wl_args(primary, &["--no-newline", "--type", mime]),
+            )
+        })
+    }
+
+    fn write(&mut self, entry: &ClipboardEntry, mime: Option<&str>) -> Result<(), String> {
+        write_as(entry, mime, |mime, data| {
+            pipe_into("wl-copy", &wl_args(self.primary, &["--type", mime]), data)
+        })
+    }
+}
+
+fn wl_args<'a>(primary: bool, args: &[&'a str]) -> Vec<&'a str> {
+    let primary = primary.then_some("--primary");
+    primary.into_iter().chain(args.iter().copied()).collect()
+}
+
+// xclip, for x11 setups where arboard cannot set the clipboard, and for the
+// primary selection, which arboard does not reach everywhere
+pub struct XClip {
+    // "clipboard" or "primary"
+    selection: &'static str,
+}
+
+impl ClipboardBackend for XClip {
+    fn name(&self) -> &'static str {
+        "xclip"
+    }
+
+    fn read(&mut self) -> Result<Option<ClipboardEntry>, String> {
+        let selection = self.selection;
+        let types: Vec<String> =
+            output_of("xclip", &["-selection", selection, "-o", "-t", "TARGETS"])
+                .map(|targets| {
+                    String::from_utf8_lossy(&targets)
+                        .lines()
+                        .map(|t| t.to_string())
+                        .collect()
+                })
+                .unwrap_or_default();
+        read_offered(&types, |mime| {
+            output_of("xclip", &["-selection", selection, "-o", "-t", mime])
+        })
+    }
+
+    fn write(&mut self, entry: &ClipboardEntry, mime: Option<&str>) -> Result<(), String> {
+        write_as(entry, mime, |mime, data| {
+            pipe_into(
+                "xclip",
+                &["-selection", self.selection, "-t", mime, "-i"],
+                data,
+            )
+        })
+    }
+}
+
+// the entry out of what a clipboard tool says is offered: text, with its
+// html if there is any, or else an image
+fn read_offered(
+    types: &[String],
+    paste: impl Fn(&str) -> Option<Vec<u8>>,
+) -> Result<Option<ClipboardEntry>, String> {
+    if let Some(text_type) = types.iter().find(|t| is_plain_text(t)) {
+        let text = paste(text_type).ok_or(format!("could not read {}", text_type))?;
+        let text = String::from_utf8(text).map_err(|_| format!("{} is not utf-8", text_type))?;
+        let html = types
+            .iter()
+            .any(|t| t == "text/html")
+            .then(|| paste("text/html").and_then(|h| String::from_utf8(h).ok()))
+            .flatten();
+        return Ok(Some(match html {
+            Some(html) => ClipboardEntry::Html {
+                html,
+                alt_text: text,
+            },
+            None => ClipboardEntry::Text(text),
+        }));
+    }
+
+    // png first, it is lossless
+    let Some(image_type) = ["image/png", "image/jpeg"]
+        .into_iter()
+        .find(|&mime| types.iter().any(|t| t == mime))
+    else {
+        // an image we cannot decode is not the same as an empty clipboard
+        return match types.iter().find(|t| t.starts_with("image/")) {
+            Some(other) => Err(format!("{} is not supported, only png and jpeg", other)),
+            None => Ok(None),
+        };
+    };
+    let bytes = paste(image_type).ok_or(format!("could not read {}", image_type))?;
+    SerializableImage::decode(&bytes)
+        .map(|image| Some(ClipboardEntry::Image(image)))
+        .map_err(|e| format!("unreadable {}: {}", image_type, e))
+}
+
+// sets the clipboard through a tool that offers a single type at a time
+fn write_as(
+    entry: &ClipboardEntry,
+    mime: Option<&str>,
+    copy: impl Fn(&str, &[u8]) -> Result<(), String>,
+) -> Result<(), String> {
+    match entry {
+        ClipboardEntry::Text(t) => copy(mime.unwrap_or("text/plain"), t.as_bytes()),
+        ClipboardEntry::Image(i) => {
+            let png = i.to_png().ok_or("image bytes do not match its size")?;
+            copy("image/png", &png)
+        }
+        // html is of no use to whatever only takes text
+        ClipboardEntry::Html { .. } => Err("cannot offer html with its text".into()),
+    }
+}
+
+// the types text is offered as, besides the text/plain ones
+fn is_plain_text(mime: &str) -> bool {
+    mime.starts_with("text/plain") || matches!(mime, "UTF8_STRING" | "STRING" | "TEXT")
+}
+
+// content types the wayland clipboard, or primary selection, currently
+// offers, empty elsewhere
+fn wayland_types(primary: bool) -> Vec<String> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_none() {
+        return Vec::new();
+    }
+
+    match Command::new("wl-paste")
+        .args(wl_args(primary, &["--list-types"]))
+        .output()
+    {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|t| t.to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn output_of(program: &str, args: &[&str]) -> Option<Vec<u8>> {
+    trace!("running {} {:?}", program, args);
+    match Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => Some(output.stdout),
+        Ok(_) => {
+            debug!("{} {:?} failed", program, args);
+            None
+        }
+        Err(e) => {
+            debug!("{} couldnt start: {}", program, e);
+            None
+        }
+    }
+}
+
+fn pipe_into(program: &str, args: &[&str], data: &[u8]) -> Result<(), String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("{} couldnt start: {}", program, e))?;
+    let written = child
+        .stdin
+        .take()
+        .map(|mut stdin| stdin.write_all(data).is_ok())
+        .unwrap_or(false);
+    match child.wait() {
+        Ok(status) if written && status.success() => Ok(()),
+        _ => Err(format!("{} failed", program)),
+    }
+}
+
+// the backends for a session, the one to try first up front. wl-clipboard
+// is of no use outside wayland, arboard is still the fallback on it. on x11
+// arboard goes first and xclip after, if arboard cannot even connect to the
+// x server xclip would not either. the primary selection only goes through
+// the command line tools
+fn backends_for(session: Session, primary: bool) -> Result<Vec<Box<dyn ClipboardBackend>>, String> {
+    let mut backends: Vec<Box<dyn ClipboardBackend>> = Vec::new();
+    if primary {
+        let backend: Box<dyn ClipboardBackend> = match session {
+            Session::Wayland => Box::new(WlClipboard { primary }),
+            Session::X11 => Box::new(XClip {
+                selection: "primary",
+            }),
+            Session::Other => return Err("there is no primary selection here".into()),
+        };
+        return Ok(vec![backend]);
+    }
+    if session == Session::Wayland {
+        backends.push(Box::new(WlClipboard { primary }));
+    }
+    match Arboard::open() {
+        Ok(arboard) => backends.push(Box::new(arboard)),
+        Err(e) if backends.is_empty() => return Err(e),
+        Err(e) => debug!("unable to open arboard: {}", e),
+    }
+    if session == Session::X11 {
+        backends.push(Box::new(XClip {
+            selection: "clipboard",
+        }));
+    }
+    Ok(backends)
+}
+
+// the system clipboard, through whichever backend works first
+pub struct SystemClipboard {
+    session: Session,
+    // the primary selection rather than the clipboard
+    primary: bool,
+    backends: Vec<Box<dyn ClipboardBackend>>,
+}
+
+impl SystemClipboard {
+    pub fn open() -> Result<Self, String> {
+        Self::open_selection(false)
+    }
+
+    // with primary, the primary selection on x11 and wayland: what was last
+    // selected, pasted with a middle click
+    pub fn open_selection(primary: bool) -> Result<Self, String> {
+        let session = Session::detect();
+        let backends = backends_for(session, primary)?;
+        trace!(
+            "{:?} session, primary {}, clipboard backends {:?}",
+            session,
+            primary,
+            backends.iter().map(|b| b.name()).collect::<Vec<_>>()
+        );
+        Ok(SystemClipboard {
+            session,
+            primary,
+            backends,
+        })
+    }
+
+    // the content, plus the content type it was primarily offered as. when
+    // a backend reads nothing the next one gets a go, and the clipboard only
+    // counts as empty if none failed to read it
+    pub fn read(&mut self) -> Result<Option<(ClipboardEntry, String)>, String> {
+        let mut failures = Vec::new();
+        let entry = self
+            .backends
+            .iter_mut()
+            .find_map(|backend| match backend.read() {
+                Ok(entry) => {
+                    let entry = entry.filter(|e| !is_blank(e));
+                    if entry.is_none() {
+                        debug!("nothing read from the clipboard via {}", backend.name());
+                    }
+                    entry
+                }
+                Err(e) => {
+                    debug!("unable to read the clipboard via {}: {}", backend.name(), e);
+                    failures.push(format!("{}: {}", backend.name(), e));
+                    None
+                }
+            });
+        let entry = match entry {
+            Some(entry) => entry,
+            None if failures.is_empty() => return Ok(None),
+            None => return Err(failures.join(", ")),
+        };
+
+        let types = match self.session {
+            Session::Wayland => wayland_types(self.primary),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() {
+            trace!("clipboard offers {:?}", types);
+        }
+        let mime = primary_mime(&entry, &types);
+        Ok(Some((entry, mime)))
+    }
+
+    pub fn write(&mut self, entry: &ClipboardEntry, mime: Option<&str>) -> Result<(), String> {
+        let mut failures = Vec::new();
+        for backend in &mut self.backends {
+            match backend.write(entry, mime) {
+                Ok(()) => {
+                    debug!("set the clipboard via {}", backend.name());
+                    return Ok(());
+                }
+                Err(e) => {
+                    debug!("unable to set the clipboard via {}: {}", backend.name(), e);
+                    failures.push(format!("{}: {}", backend.name(), e));
+                }
+            }
+        }
+        Err(failures.join(", "))
+    }
+}
+
+impl Debug for SystemClipboard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.primary {
+            true => write!(f, "Clipboard({:?}, primary)", self.session),
+            false => write!(f, "Clipboard({:?})", self.session),
+        }
+    }
+}
+
+// nothing worth keeping, like a selection of only whitespace
+pub fn is_blank(entry: &ClipboardEntry) -> bool {
+    match entry {
+        ClipboardEntry::Text(t) => t.trim().is_empty(),
+        ClipboardEntry::Html { html, alt_text } => {
+            html.trim().is_empty() && alt_text.trim().is_empty()
+        }
+        ClipboardEntry::Image(i) => i.is_empty(),
+    }
+}
+
+fn primary_mime(entry: &ClipboardEntry, types: &[String]) -> String {
+    match entry {
+        ClipboardEntry::Html { .. } => "text/html".to_string(),
+        ClipboardEntry::Image(_) => types
+            .iter()
+            .find(|t| t.starts_with("image/"))
+            .cloned()
+            .unwrap_or_else(|| "image/png".to_string()),
+        ClipboardEntry::Text(_) => types
+            .iter()
+            .find(|t| t.starts_with("text/") && t.as_str() != "text/html")
+            .cloned()
+            .unwrap_or_else(|| "text/plain".to_string()),
+    }
+}
@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use tracing::warn;
+
+use crate::http_server::TlsConfig;
+use crate::platform::runtime_path;
+
+// everything the daemon can be configured with, read once at startup from
+// $XDG_CONFIG_HOME/slate/config.toml (or SLATE_CONFIG). missing fields fall
+// back to the defaults below, and the SLATE_* env vars override the file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub socket_path: String,
+    pub db_path: String,
+    // port the http server of every node listens on
+    pub port: u16,
+    pub anti_entropy_interval_ms: u64,
+    pub watch_interval_ms: u64,
+    // how many hops a locally copied entry travels through gossip
+    pub ttl: u64,
+    // how many peers an entry is gossiped to at once
+    pub max_per_round: u64,
+    // how many entries `slate history` lists
+    pub max_history: usize,
+    // files larger than this are refused by `slate upload`
+    pub max_upload_bytes: u64,
+    // zstd level uploaded files are stored with, 1 (fast) to 22 (small)
+    pub compression_level: i32,
+    // where peers come from, "tailscale" or "static"
+    pub discovery: String,
+    // for static discovery: our own name, peers as "host:ip", and the
+    // address to listen on (every interface if unset). with tailscale the
+    // peers are added to the tailnet's, and the name is used without it
+    pub host_name: Option<String>,
+    pub peers: Vec<String>,
+    pub bind_addr: Option<IpAddr>,
+    // shared secret for the http api, the same on every device
+    pub token: Option<String>,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            socket_path: if cfg!(windows) {
+                r"\\.\pipe\slate_daemon".to_string()
+            } else {
+                "/tmp/slate_daemon.sock".to_string()
+            },
+            db_path: runtime_path("slate_daemon.sqlite")
+                .to_string_lossy()
+                .to_string(),
+            port: 3000,
+            anti_entropy_interval_ms: 3 * 60 * 1000,
+            watch_interval_ms: 1000,
+            ttl: 1,
+            max_per_round: 5,
+            max_history: 20,
+            max_upload_bytes: 100 * 1024 * 1024,
+            compression_level: 3,
+            discovery: "tailscale".to_string(),
+            host_name: None,
+            peers: Vec::new(),
+            bind_addr: None,
+            token: None,
+            tls: None,
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/slate, or ~/.config/slate, or %APPDATA%/slate on windows
+pub fn config_dir() -> Option<String> {
+    match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => Some(format!("{}/slate", dir)),
+        _ => match std::env::var("HOME") {
+            Ok(home) => Some(format!("{}/.config/slate", home)),
+            Err(_) => Some(format!("{}/slate", std::env::var("APPDATA").ok()?)),
+        },
+    }
+}
+
+fn config_path() -> Option<String> {
+    match std::env::var("SLATE_CONFIG") {
+        Ok(path) => Some(path),
+        Err(_) => Some(format!("{}/config.toml", config_dir()?)),
+    }
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let mut config = match config_path().map(|path| (fs::read_to_string(&path), path)) {
+            Some((Ok(content), path)) => toml::from_str(&content).unwrap_or_else(|e| {
+                warn!("invalid config {}, using defaults: {}", path, e);
+                Config::default()
+            }),
+            _ => Config::default(),
+        };
+
+        let defaults = Config::default();
+        if config.anti_entropy_interval_ms == 0 {
+            config.anti_entropy_interval_ms = defaults.anti_entropy_interval_ms;
+        }
+        if config.watch_interval_ms == 0 {
+            config.watch_interval_ms = defaults.watch_interval_ms;
+        }
+        // 0 would gossip to no one, a ttl of 0 is fine and leaves it all to
+        // anti entropy
+        if config.max_per_round == 0 {
+            warn!(
+                "max_per_round must be at least 1, using {}",
+                defaults.max_per_round
+            );
+            config.max_per_round = defaults.max_per_round;
+        }
+        if !zstd::compression_level_range().contains(&config.compression_level) {
+            warn!(
+                "invalid compression_level {}, using {}",
+                config.compression_level, defaults.compression_level
+            );
+            config.compression_level = defaults.compression_level;
+        }
+
+        if let Ok(path) = std::env::var("SLATE_SOCKET") {
+            if !path.is_empty() {
+                config.socket_path = path;
+            }
+        }
+        if let Ok(v) = std::env::var("SLATE_PORT") {
+            match v.parse::<u16>() {
+                Ok(port) => config.port = port,
+                Err(_) => warn!("invalid SLATE_PORT {}, using {}", v, config.port),
+            }
+        }
+        if let Ok(v) = std::env::var("SLATE_ANTI_ENTROPY_INTERVAL_MS") {
+            match v.parse::<u64>() {
+                Ok(ms) if ms > 0 => config.anti_entropy_interval_ms = ms,
+                _ => warn!(
+                    "invalid SLATE_ANTI_ENTROPY_INTERVAL_MS {}, using {}",
+                    v, config.anti_entropy_interval_ms
+                ),
+            }
+        }
+        if let Ok(v) = std::env::var("SLATE_TTL") {
+            match v.parse::<u64>() {
+                Ok(ttl) => config.ttl = ttl,
+                Err(_) => warn!("invalid SLATE_TTL {}, using {}", v, config.ttl),
+            }
+        }
+        if let Ok(v) = std::env::var("SLATE_MAX_PER_ROUND") {
+            match v.parse::<u64>() {
+                Ok(max) if max > 0 => config.max_per_round = max,
+                _ => warn!(
+                    "invalid SLATE_MAX_PER_ROUND {}, using {}",
+                    v, config.max_per_round
+                ),
+            }
+        }
+        if let Some(ms) = std::env::var("SLATE_WATCH_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|&ms| ms > 0)
+        {
+            config.watch_interval_ms = ms;
+        }
+        config.token = auth_token(config.token.take());
+        if let Some(tls) = tls_config() {
+            config.tls = Some(tls);
+        }
+
+        config
+    }
+
+    // the effective configuration as toml, without giving away the token
+    pub fn show(&self) -> String {
+        let path = config_path().unwrap_or_else(|| "<no config dir>".to_string());
+        format!(
+            "# {}\n{}",
+            path,
+            toml::to_string(&self.redacted()).expect("failed to serialize config")
+        )
+    }
+
+    pub fn show_json(&self) -> String {
+        serde_json::to_string(&self.redacted()).expect("failed to serialize config")
+    }
+
+    fn redacted(&self) -> Config {
+        let mut shown = self.clone();
+        if shown.token.is_some() {
+            shown.token = Some("<hidden>".to_string());
+        }
+        shown
+    }
+}
+
+// SLATE_TOKEN, or the file at SLATE_TOKEN_FILE, then the config file, and
+// lastly the file at ~/.config/slate/token
+fn auth_token(from_file: Option<String>) -> Option<String> {
+    if let Ok(token) = std::env::var("SLATE_TOKEN") {
+        return Some(token.trim().to_string()).filter(|t| !t.is_empty());
+    }
+
+    let path = match std::env::var("SLATE_TOKEN_FILE") {
+        Ok(path) => path,
+        Err(_) => {
+            if let Some(token) = from_file.filter(|t| !t.trim().is_empty()) {
+                return Some(token.trim().to_string());
+            }
+            format!("{}/token", config_dir()?)
+        }
+    };
+    fs::read_to_string(path)
+        .ok()
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+// tls is opt in: set SLATE_TLS_CERT and SLATE_TLS_KEY to pem files, and
+// SLATE_TLS_CA to the ca that signed the peers' certificates if it is self made
+fn tls_config() -> Option<TlsConfig> {
+    let cert_path = std::env::var("SLATE_TLS_CERT").ok()?;
+    let key_path = std::env::var("SLATE_TLS_KEY").ok()?;
+    Some(TlsConfig {
+        cert_path,
+        key_path,
+        ca_path: std::env::var("SLATE_TLS_CA").ok(),
+    })
+}
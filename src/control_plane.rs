@@ -1,7 +1,13 @@
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime},
 };
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
@@ -10,76 +16,563 @@ use tokio::{
     time::{sleep, Duration},
 };
 
-use http::{header::HOST, Request};
-use http_body_util::{BodyExt, Full};
-use hyper::body::Bytes;
-use hyper_util::client::legacy::Client;
-use hyperlocal::{UnixClientExt, UnixConnector, Uri};
+use tracing::{debug, error, info, trace, warn};
 use ulid::Ulid;
 
-use crate::db::{ClipboardEntry, Clock, DBMessage};
+use crate::config::Config;
+use crate::crypto::{Cipher, Payload, Records};
+use crate::db::{ClipboardEntry, ClipboardRecord, Clock, DBMessage, Origin, SharedFile};
+use crate::discovery::PeerDiscovery;
+use crate::metrics::Metrics;
 
-const PORT: u64 = 3000;
-const ANTI_ENTROPY_TIMEOUT_MS: u64 = 3 * 60 * 1000;
-const TTL: u64 = 1;
-const MAX_PER_ROUND: u64 = 5;
+// entries handled within this window are not stored or gossiped again
+const SEEN_WINDOW: Duration = Duration::from_secs(5 * 60);
+const SEEN_CAPACITY: usize = 1024;
+// how long a single request to a peer may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// how long a peer has to answer the health probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+// how long downloading a single file from a peer may take
+const FILE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// how long a discovered peer list is reused before asking again
+const NEIGHBOR_CACHE_TTL: Duration = Duration::from_secs(10);
+// waits between attempts when a peer request fails
+const RETRY_BACKOFF_MS: [u64; 3] = [100, 400, 1600];
+// how long a peer is left alone after failing, doubled with every further
+// failure in a row up to PEER_BACKOFF_MAX
+const PEER_BACKOFF: Duration = Duration::from_secs(30);
+const PEER_BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
 
+// field names mirror the tailscale local api json
+#[allow(non_snake_case)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PeerInfo {
-    HostName: String,
-    TailscaleIPs: Vec<String>,
-    Online: bool,
+    pub HostName: String,
+    pub TailscaleIPs: Vec<String>,
+    pub Online: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Gossip {
+    pub entry: Payload,
+    #[serde(flatten)]
+    pub meta: GossipMeta,
+}
+
+// everything in a gossip message but the entry
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GossipMeta {
     pub clock: Clock,
-    pub entry: ClipboardEntry,
+    // hops the entry may still travel after reaching the receiver
     pub ttl: u64,
+    #[serde(default)]
+    pub origin: Option<Origin>,
+    #[serde(default)]
+    pub mime: Option<String>,
+    // ulid of the entry, the same on every node
+    #[serde(default)]
+    pub key: Option<String>,
+    // unix seconds after which every node deletes the entry
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    // the entry with this key was deleted, the entry sent is left empty
+    #[serde(default)]
+    pub deleted: bool,
 }
 
-pub fn is_outdated(clock: &Clock, incoming: &Clock) -> bool {
+impl GossipMeta {
+    // what the entry is sealed with when a key is set. the clock goes in
+    // sorted, both ends have to come up with the same bytes
+    pub fn sealed_bytes(&self) -> Vec<u8> {
+        let clock: BTreeMap<_, _> = self.clock.iter().collect();
+        serde_json::to_vec(&(
+            clock,
+            self.ttl,
+            &self.origin,
+            &self.mime,
+            &self.key,
+            self.expires_at,
+            self.deleted,
+        ))
+        .expect("failed to serialize gossip")
+    }
+}
+
+// the addresses to reach a peer at, ipv4 before ipv6. empty for peers
+// without one, e.g. freshly added or expired nodes
+fn peer_ips(peer: &PeerInfo) -> Vec<IpAddr> {
+    let mut ips: Vec<IpAddr> = peer
+        .TailscaleIPs
+        .iter()
+        .filter_map(|ip| ip.parse().ok())
+        .collect();
+    ips.sort_by_key(|ip| ip.is_ipv6());
+    ips
+}
+
+fn peer_url(scheme: &str, ip: IpAddr, port: u16, path: &str) -> String {
+    // SocketAddr puts ipv6 addresses in brackets
+    format!("{}://{}/{}", scheme, SocketAddr::new(ip, port), path)
+}
+
+// how requests to a peer went lately
+#[derive(Debug, Clone, Default)]
+pub struct PeerHealth {
+    // requests in a row that failed, after their retries
+    pub failures: u32,
+    // the peer is skipped until then
+    pub retry_at: Option<Instant>,
+    // the address it last answered on, tried first next time
+    pub last_address: Option<IpAddr>,
+}
+
+impl PeerHealth {
+    // how much longer the peer is skipped, None if it is not
+    pub fn backoff_left(&self) -> Option<Duration> {
+        self.retry_at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+            .filter(|left| !left.is_zero())
+    }
+}
+
+// http requests to peers. a peer can have several addresses, like an ipv4
+// and an ipv6 one on the tailnet, and only some of them may be reachable
+#[derive(Clone)]
+struct PeerClient {
+    // carries the shared auth token, if one is configured
+    client: reqwest::Client,
+    // http or https, every node is expected to use the same
+    scheme: &'static str,
+    // port the http server of every node listens on
+    port: u16,
+    health: Arc<Mutex<HashMap<String, PeerHealth>>>,
+}
+
+impl PeerClient {
+    // a peer's addresses in the order to try them, the one it last answered
+    // on first
+    fn addresses(&self, peer: &PeerInfo) -> Vec<IpAddr> {
+        let mut ips = peer_ips(peer);
+        let last = self
+            .health
+            .lock()
+            .expect("failed to acquire lock")
+            .get(&peer.HostName)
+            .and_then(|h| h.last_address);
+        if let Some(last) = last {
+            ips.sort_by_key(|&ip| ip != last);
+        }
+        ips
+    }
+
+    // sends a request for `path` to a peer, trying each of its addresses in
+    // turn, and all of them again with backoff when none can be reached or
+    // they answer with a server error. other statuses are returned as they are
+    async fn send(
+        &self,
+        peer: &PeerInfo,
+        path: &str,
+        request: impl Fn(&reqwest::Client, reqwest::Url) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, String> {
+        let ips = self.addresses(peer);
+        if ips.is_empty() {
+            return Err(format!("{} has no usable address", peer.HostName));
+        }
+        let mut delays = RETRY_BACKOFF_MS.iter();
+        loop {
+            let mut error = String::new();
+            for &ip in &ips {
+                let url = reqwest::Url::parse(&peer_url(self.scheme, ip, self.port, path))
+                    .expect("failed to build peer url");
+                match request(&self.client, url).send().await {
+                    Ok(response) if !response.status().is_server_error() => {
+                        self.health.lock().expect("failed to acquire lock").insert(
+                            peer.HostName.clone(),
+                            PeerHealth {
+                                last_address: Some(ip),
+                                ..PeerHealth::default()
+                            },
+                        );
+                        return Ok(response);
+                    }
+                    Ok(response) => {
+                        error = format!("{} from {}", response.status(), response.url())
+                    }
+                    Err(e) => error = e.to_string(),
+                }
+            }
+            match delays.next() {
+                Some(&ms) => sleep(Duration::from_millis(ms)).await,
+                None => {
+                    self.record_failure(&peer.HostName);
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    // whether slate answers on the peer at all, with a single quick request
+    // per address and no retries. tailscale can list a device as online while
+    // its daemon is stopped, a failed probe backs off like a failed request
+    async fn probe(&self, peer: &PeerInfo) -> bool {
+        for ip in self.addresses(peer) {
+            let url = peer_url(self.scheme, ip, self.port, "health");
+            match self.client.get(url).timeout(PROBE_TIMEOUT).send().await {
+                Ok(response) if !response.status().is_server_error() => {
+                    let mut health = self.health.lock().expect("failed to acquire lock");
+                    health
+                        .entry(peer.HostName.clone())
+                        .or_default()
+                        .last_address = Some(ip);
+                    return true;
+                }
+                Ok(response) => trace!("{} from {} for the probe", response.status(), ip),
+                Err(e) => trace!("probe of {} at {} failed: {}", peer.HostName, ip, e),
+            }
+        }
+        self.record_failure(&peer.HostName);
+        false
+    }
+
+    fn record_failure(&self, host: &str) {
+        let mut health = self.health.lock().expect("failed to acquire lock");
+        let health = health.entry(host.to_string()).or_default();
+        health.failures += 1;
+        let backoff = PEER_BACKOFF
+            .saturating_mul(2u32.saturating_pow(health.failures - 1))
+            .min(PEER_BACKOFF_MAX);
+        health.retry_at = Some(Instant::now() + backoff);
+        debug!(
+            "{} failed {} times in a row, skipping it for {:?}",
+            host, health.failures, backoff
+        );
+    }
+
+    // whether the peer failed lately and is still left alone
+    fn backing_off(&self, peer: &PeerInfo) -> bool {
+        self.health
+            .lock()
+            .expect("failed to acquire lock")
+            .get(&peer.HostName)
+            .is_some_and(|h| h.backoff_left().is_some())
+    }
+
+    fn health(&self) -> HashMap<String, PeerHealth> {
+        self.health.lock().expect("failed to acquire lock").clone()
+    }
+
+    // a client that starts from the same health but keeps what it learns to
+    // itself, for requests that must not back off or revive a peer
+    fn detached(&self) -> PeerClient {
+        PeerClient {
+            health: Arc::new(Mutex::new(self.health())),
+            ..self.clone()
+        }
+    }
+}
+
+// how one clock relates to another. a clock is behind when none of its
+// counters is higher and one is lower, and two clocks are concurrent when
+// each has seen entries the other has not, like two hosts that both copied
+// while apart. entries are keyed by ulid and never overwritten, so concurrent
+// entries are all kept, and the newest ulid is what pastes first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockOrder {
+    Less,
+    Greater,
+    Equal,
+    Concurrent,
+}
+
+// how `a` relates to `b`, a host missing from a clock counts as 0
+pub fn compare_clocks(a: &Clock, b: &Clock) -> ClockOrder {
+    let mut behind = false;
+    let mut ahead = false;
+    for key in a.keys().chain(b.keys()) {
+        let ours = a.get(key).copied().unwrap_or_default();
+        let theirs = b.get(key).copied().unwrap_or_default();
+        behind |= ours < theirs;
+        ahead |= ours > theirs;
+    }
+    match (behind, ahead) {
+        (false, false) => ClockOrder::Equal,
+        (true, false) => ClockOrder::Less,
+        (false, true) => ClockOrder::Greater,
+        (true, true) => ClockOrder::Concurrent,
+    }
+}
+
+// how many counters `incoming` is ahead of `clock` by, summed over hosts. a
+// rough count of the entries it has that `clock` lacks, deletes included
+fn counters_ahead(clock: &Clock, incoming: &Clock) -> u64 {
     incoming
         .iter()
-        .any(|(key, &incoming_val)| match clock.get(key) {
-            Some(&local_val) => incoming_val > local_val,
-            None => true,
-        })
+        .map(|(host, theirs)| theirs.saturating_sub(clock.get(host).copied().unwrap_or_default()))
+        .sum()
+}
+
+// whether `incoming` has seen anything `clock` has not
+pub fn is_outdated(clock: &Clock, incoming: &Clock) -> bool {
+    matches!(
+        compare_clocks(clock, incoming),
+        ClockOrder::Less | ClockOrder::Concurrent
+    )
+}
+
+// takes the higher counter of every host, so a stale incoming clock can
+// never move one of ours back
+pub fn merge_clock(local: &mut Clock, incoming: &Clock) {
+    for (key, &value) in incoming {
+        let merged = local.get(key).map_or(value, |&ours| ours.max(value));
+        local.insert(key.clone(), merged);
+    }
+}
+
+// moves the counter of the origin's host to the origin's, if it is the very
+// next one, and returns whether it did
+fn advance_counter(clock: &mut Clock, origin: &Origin) -> bool {
+    let counter = clock.entry(origin.host.clone()).or_insert(0);
+    if origin.counter != *counter + 1 {
+        return false;
+    }
+    *counter = origin.counter;
+    true
+}
+
+// keys of the entries gossip brought lately, oldest first, so one that
+// arrives again over another path is dropped
+#[derive(Default)]
+struct Seen(VecDeque<(Ulid, Instant)>);
+
+impl Seen {
+    // records the key, false if it was already recorded within SEEN_WINDOW
+    // of `now`
+    fn mark(&mut self, key: Ulid, now: Instant) -> bool {
+        while self
+            .0
+            .front()
+            .is_some_and(|(_, at)| now.saturating_duration_since(*at) > SEEN_WINDOW)
+        {
+            self.0.pop_front();
+        }
+        if self.0.iter().any(|(k, _)| *k == key) {
+            return false;
+        }
+        if self.0.len() >= SEEN_CAPACITY {
+            self.0.pop_front();
+        }
+        self.0.push_back((key, now));
+        true
+    }
+
+    fn forget(&mut self, key: Ulid) {
+        self.0.retain(|(k, _)| *k != key);
+    }
+}
+
+// the last peer list we discovered, and when
+#[derive(Default)]
+struct Neighbors {
+    peers: Vec<PeerInfo>,
+    loaded_at: Option<Instant>,
 }
 
 pub struct Node {
     host_name: String,
-    neighbors: Arc<Mutex<Vec<PeerInfo>>>,
+    neighbors: Arc<Mutex<Neighbors>>,
+    started_at: SystemTime,
+    // how many hops a locally copied entry travels through gossip
+    ttl: u64,
+    // how many peers an entry is gossiped to at once
+    max_per_round: u64,
+    peers: PeerClient,
+    // seals gossiped entries, if a key is configured
+    cipher: Option<Cipher>,
+    // keys of recently handled entries, oldest first
+    seen: Arc<Mutex<Seen>>,
+    discovery: Arc<dyn PeerDiscovery>,
+    files: FilePuller,
+    metrics: Arc<Metrics>,
+}
+
+// pulls the files peers have and we do not. files can be large, so this
+// runs on its own task rather than holding up gossip and the clipboard sync
+// while they download, one pull at a time
+#[derive(Clone)]
+struct FilePuller {
+    peers: PeerClient,
+    busy: Arc<AtomicBool>,
+}
+
+impl FilePuller {
+    fn spawn_pull(&self, peers: Vec<PeerInfo>, mut tx: mpsc::Sender<DBMessage>) {
+        if self.busy.swap(true, Ordering::SeqCst) {
+            debug!("still pulling files from an earlier sync");
+            return;
+        }
+        let puller = self.clone();
+        tokio::spawn(async move {
+            for peer in peers {
+                let stored = puller.pull(&peer, &mut tx).await;
+                if stored > 0 {
+                    info!("pulled {} files from {}", stored, peer.HostName);
+                }
+            }
+            puller.busy.store(false, Ordering::SeqCst);
+        });
+    }
+
+    // pulls the files a peer has and we do not, returns how many were stored.
+    // a file we have under the same name but with other content is kept as is
+    async fn pull(&self, peer: &PeerInfo, tx: &mut mpsc::Sender<DBMessage>) -> usize {
+        let host = &peer.HostName;
+        let remote = self
+            .peers
+            .send(peer, "files", |client, url| client.get(url));
+        let remote: Vec<SharedFile> = match remote.await {
+            Ok(response) => match response.json().await {
+                Ok(files) => files,
+                Err(e) => {
+                    warn!("Failed to parse files from {}: {}", host, e);
+                    return 0;
+                }
+            },
+            Err(e) => {
+                warn!("Failed to fetch files from {}: {}", host, e);
+                return 0;
+            }
+        };
+        if remote.is_empty() {
+            return 0;
+        }
+
+        let local = {
+            let (x, y) = oneshot::channel();
+            let msg = DBMessage {
+                cmd: crate::db::DBCommand::ListFiles,
+                sender: x,
+            };
+            tx.send(msg).await.expect("failed to send db message");
+            match y.await.expect("failed to recieve msg") {
+                Ok(crate::db::Response::Files { files }) => files,
+                _ => {
+                    error!("failed to list local files");
+                    return 0;
+                }
+            }
+        };
+
+        let mut stored = 0;
+        for file in remote {
+            if let Some(ours) = local.iter().find(|f| f.name == file.name) {
+                if ours.hash.as_ref().is_some_and(|h| h != &file.hash) {
+                    debug!("{} has a different {}, keeping ours", host, file.name);
+                }
+                continue;
+            }
+
+            let request = self.peers.send(peer, "download", |client, mut url| {
+                url.path_segments_mut()
+                    .expect("http urls have a path")
+                    .push(&file.name);
+                client.get(url).timeout(FILE_TIMEOUT)
+            });
+            let content = match request.await {
+                Ok(response) => match response.error_for_status() {
+                    Ok(response) => response.bytes().await.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                },
+                Err(e) => Err(e),
+            };
+            let content = match content {
+                Ok(content) => content.to_vec(),
+                Err(e) => {
+                    warn!("Failed to download {} from {}: {}", file.name, host, e);
+                    continue;
+                }
+            };
+
+            let (x, y) = oneshot::channel();
+            let name = file.name.clone();
+            let msg = DBMessage {
+                cmd: crate::db::DBCommand::StoreFile { file, content },
+                sender: x,
+            };
+            tx.send(msg).await.expect("failed to send db message");
+            match y.await.expect("failed to recieve msg") {
+                Ok(_) => stored += 1,
+                Err(e) => warn!("failed to store {} from {}: {}", name, host, e),
+            }
+        }
+        stored
+    }
 }
 
 impl Node {
-    pub async fn new() -> Self {
-        let host_name = {
-            let socket_path = "/var/run/tailscale/tailscaled.sock";
-            let url_path = "/localapi/v0/status";
-            let uri = Uri::new(socket_path, url_path);
-
-            let req = Request::get(uri)
-                .header(HOST, "local-tailscaled.sock")
-                .body(Full::new(Bytes::new()))
-                .unwrap();
-
-            let client: Client<UnixConnector, Full<Bytes>> = Client::unix();
-
-            // let res = client.get(uri).await.unwrap();
-            let res = client.request(req).await.unwrap();
-            let body = res.collect().await.unwrap().to_bytes();
-            let json_value: serde_json::Value =
-                serde_json::from_slice(&body).expect("failed to parse json");
-
-            // Extract just the "Peer" object
-            let name_json = &json_value["Self"]["HostName"];
-            serde_json::from_value(name_json.clone()).unwrap()
+    pub async fn new(
+        started_at: SystemTime,
+        config: &Config,
+        cipher: Option<Cipher>,
+        discovery: Arc<dyn PeerDiscovery>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        let tls = &config.tls;
+        let host_name = discovery.self_host().await?;
+        let mut headers = http::HeaderMap::new();
+        if let Some(token) = &config.token {
+            let value = format!("Bearer {}", token)
+                .parse()
+                .map_err(|_| "auth token is not a valid header value".to_string())?;
+            headers.insert(http::header::AUTHORIZATION, value);
+        }
+        let mut builder = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(REQUEST_TIMEOUT);
+        if let Some(ca_path) = tls.as_ref().and_then(|tls| tls.ca_path.as_ref()) {
+            let pem =
+                std::fs::read(ca_path).map_err(|e| format!("failed to read tls ca: {}", e))?;
+            let ca = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| format!("failed to parse tls ca: {}", e))?;
+            builder = builder.add_root_certificate(ca);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| format!("failed to build http client: {}", e))?;
+        let peers = PeerClient {
+            client,
+            scheme: if tls.is_some() { "https" } else { "http" },
+            port: config.port,
+            health: Arc::new(Mutex::new(HashMap::new())),
         };
-        Node {
+
+        Ok(Node {
             host_name,
-            neighbors: Arc::new(Mutex::new(Vec::new())),
-        }
+            neighbors: Arc::new(Mutex::new(Neighbors::default())),
+            started_at,
+            ttl: config.ttl,
+            max_per_round: config.max_per_round,
+            peers: peers.clone(),
+            cipher,
+            seen: Arc::new(Mutex::new(Seen::default())),
+            discovery,
+            files: FilePuller {
+                peers,
+                busy: Arc::new(AtomicBool::new(false)),
+            },
+            metrics,
+        })
+    }
+
+    // records the key, false if it was already handled within SEEN_WINDOW
+    fn mark_seen(&self, key: Ulid) -> bool {
+        let mut seen = self.seen.lock().expect("failed to acquire lock");
+        seen.mark(key, Instant::now())
+    }
+
+    // for an entry that failed to save, so a retry is not taken for a duplicate
+    fn forget_seen(&self, key: Ulid) {
+        let mut seen = self.seen.lock().expect("failed to acquire lock");
+        seen.forget(key);
     }
 
     async fn get_clock(&self, tx: &mut mpsc::Sender<DBMessage>) -> Clock {
@@ -95,7 +588,7 @@ impl Node {
         if let Ok(crate::db::Response::Clock { data }) = response {
             data
         } else {
-            eprintln!("{}", response.err().unwrap());
+            error!("{}", response.err().unwrap());
             HashMap::new()
         }
     }
@@ -108,135 +601,459 @@ impl Node {
         };
         tx.send(msg).await.expect("failed to send db message");
 
-        let _ = y.await;
+        match y.await.expect("failed to recieve msg") {
+            Ok(_) => debug!("saved clock"),
+            Err(e) => error!("failed to save clock: {}", e),
+        }
     }
 
+    // sends an entry, or its tombstone once deleted, to at most
+    // `max_per_round` online peers. `ttl` is how many hops it may still
+    // travel, each receiver forwards it with one less
+    #[allow(clippy::too_many_arguments)]
     async fn gossip(
         &self,
+        key: Ulid,
         entry: ClipboardEntry,
-        neighbor_count: u64,
+        mime: Option<String>,
+        origin: Option<Origin>,
+        expires_at: Option<u64>,
+        deleted: bool,
         ttl: u64,
         tx: &mut mpsc::Sender<DBMessage>,
     ) {
-        self.reload_neighbors().await;
+        if ttl == 0 {
+            return;
+        }
+        self.reload_neighbors(false, tx).await;
+        // in a random order, so with more peers than max_per_round a
+        // different few hear of each entry first
         let neighbors = {
-            let n = self.neighbors.lock().expect("failed to acquire lock");
-            n.clone()
+            let mut peers = self
+                .neighbors
+                .lock()
+                .expect("failed to acquire lock")
+                .peers
+                .clone();
+            peers.shuffle(&mut rand::rng());
+            peers
+        };
+        let originator = origin.as_ref().map(|o| o.host.clone());
+        let meta = GossipMeta {
+            clock: self.get_clock(tx).await,
+            ttl: ttl - 1,
+            origin,
+            mime,
+            key: Some(key.to_string()),
+            expires_at,
+            deleted,
+        };
+        let body = Gossip {
+            entry: Payload::new(entry, self.cipher.as_ref(), &meta.sealed_bytes()),
+            meta,
         };
-        let clock = self.get_clock(tx).await;
-        let client = reqwest::Client::new();
 
         let mut sent = 0;
         for n in neighbors {
-            if !n.Online {
+            if sent >= self.max_per_round {
+                break;
+            }
+            // the originator already has it, never echo it back
+            if !n.Online || Some(&n.HostName) == originator.as_ref() {
                 continue;
             };
-            let ip = n.TailscaleIPs[0].clone();
-            let endpoint = format!("http://{}:{}/gossip", ip, PORT);
-            let clock = clock.clone();
-            let entry = entry.clone();
-            let body = Gossip { clock, ttl, entry };
-            let _resp = client.post(endpoint).json(&body).send().await;
+            if peer_ips(&n).is_empty() {
+                debug!("skipping {}, it has no usable address", n.HostName);
+                continue;
+            }
+            // anti entropy brings it up to date once it is back
+            if self.peers.backing_off(&n) {
+                debug!("skipping {}, it failed lately", n.HostName);
+                continue;
+            }
+            // anti entropy catches up with peers we could not reach
+            let request = self
+                .peers
+                .send(&n, "gossip", |client, url| client.post(url).json(&body));
+            match request.await {
+                Ok(_) => Metrics::add(&self.metrics.gossip_sent, 1),
+                Err(e) => warn!("failed to gossip to {}: {}", n.HostName, e),
+            }
 
             // limit the number of messages
             sent += 1;
-            if sent > neighbor_count {
-                break;
+        }
+    }
+
+    // refreshes the peer list from discovery, unless it was loaded less than
+    // NEIGHBOR_CACHE_TTL ago and a refresh is not forced
+    async fn reload_neighbors(&self, force: bool, tx: &mut mpsc::Sender<DBMessage>) {
+        {
+            let cur = self.neighbors.lock().expect("failed to acquire lock");
+            let fresh = cur
+                .loaded_at
+                .is_some_and(|at| at.elapsed() < NEIGHBOR_CACHE_TTL);
+            if fresh && !force {
+                return;
             }
         }
+        debug!("reloading neighbors");
+        let neighbors = match self.discovery.peers().await {
+            Ok(neighbors) => neighbors,
+            Err(e) => {
+                warn!("failed to discover peers: {}", e);
+                return;
+            }
+        };
+
+        {
+            let mut cur = self.neighbors.lock().expect("failed to acquire lock");
+            Metrics::set(&self.metrics.neighbors, neighbors.len() as u64);
+            cur.peers = neighbors;
+            cur.loaded_at = Some(Instant::now());
+        }
+        self.save_neighbors(tx).await;
+    }
+
+    // remembers the peers that are online right now, so a restarted daemon
+    // can reach them before discovery has answered, or when it fails
+    async fn save_neighbors(&self, tx: &mut mpsc::Sender<DBMessage>) {
+        let peers: Vec<PeerInfo> = {
+            let cur = self.neighbors.lock().expect("failed to acquire lock");
+            cur.peers
+                .iter()
+                .filter(|n| n.Online)
+                .filter_map(|n| {
+                    Some(PeerInfo {
+                        HostName: n.HostName.clone(),
+                        TailscaleIPs: vec![self.peers.addresses(n).first()?.to_string()],
+                        Online: true,
+                    })
+                })
+                .collect()
+        };
+        let (x, y) = oneshot::channel();
+        let msg = DBMessage {
+            cmd: crate::db::DBCommand::SaveNeighbors { peers },
+            sender: x,
+        };
+        tx.send(msg).await.expect("failed to send db message");
+
+        if let Err(e) = y.await.expect("failed to recieve msg") {
+            error!("failed to save neighbors: {}", e);
+        }
     }
 
-    async fn reload_neighbors(&self) {
-        println!("reloading neighbors");
-        let socket_path = "/var/run/tailscale/tailscaled.sock";
-        let url_path = "/localapi/v0/status";
-        let uri = Uri::new(socket_path, url_path);
+    // primes the peer list with the last saved snapshot. it still counts as
+    // stale, so the first use asks discovery and only falls back to this
+    async fn load_neighbors(&self, tx: &mut mpsc::Sender<DBMessage>) {
+        let (x, y) = oneshot::channel();
+        let msg = DBMessage {
+            cmd: crate::db::DBCommand::LoadNeighbors,
+            sender: x,
+        };
+        tx.send(msg).await.expect("failed to send db message");
 
-        let req = Request::get(uri)
-            .header(HOST, "local-tailscaled.sock")
-            .body(Full::new(Bytes::new()))
-            .unwrap();
+        match y.await.expect("failed to recieve msg") {
+            Ok(crate::db::Response::Neighbors { peers }) => {
+                info!("loaded {} saved neighbors", peers.len());
+                let mut cur = self.neighbors.lock().expect("failed to acquire lock");
+                if cur.peers.is_empty() {
+                    Metrics::set(&self.metrics.neighbors, peers.len() as u64);
+                    cur.peers = peers;
+                }
+            }
+            Ok(_) => unreachable!(),
+            Err(e) => error!("failed to load neighbors: {}", e),
+        }
+    }
 
-        let client: Client<UnixConnector, Full<Bytes>> = Client::unix();
+    // hosts we currently consider part of the mesh, including ourselves.
+    // empty if we have not managed to load any neighbors yet
+    fn known_hosts(&self) -> Vec<String> {
+        let neighbors = &self.neighbors.lock().expect("failed to acquire lock").peers;
+        if neighbors.is_empty() {
+            return Vec::new();
+        }
+        let mut hosts: Vec<String> = neighbors.iter().map(|n| n.HostName.clone()).collect();
+        hosts.push(self.host_name.clone());
+        hosts
+    }
 
-        // let res = client.get(uri).await.unwrap();
-        let res = client.request(req).await.unwrap();
-        let body = res.collect().await.unwrap().to_bytes();
-        let json_value: serde_json::Value =
-            serde_json::from_slice(&body).expect("failed to parse json");
+    // strips hosts that have left the tailnet from an incoming clock, so stale
+    // gossip cannot resurrect a clock entry we already pruned
+    fn retain_known_hosts(&self, clock: &mut Clock) {
+        let hosts = self.known_hosts();
+        if hosts.is_empty() {
+            return;
+        }
+        clock.retain(|key, _| hosts.contains(key));
+    }
 
-        // Extract just the "Peer" object
-        let peers_json = &json_value["Peer"];
-        let peers: HashMap<String, PeerInfo> = serde_json::from_value(peers_json.clone()).unwrap();
+    async fn prune_clock(&self, tx: &mut mpsc::Sender<DBMessage>) {
+        let keep = self.known_hosts();
+        let (x, y) = oneshot::channel();
+        let msg = DBMessage {
+            cmd: crate::db::DBCommand::PruneClock { keep },
+            sender: x,
+        };
+        tx.send(msg).await.expect("failed to send db message");
 
-        let neighbors: Vec<PeerInfo> = peers.into_values().collect();
-        let mut cur = self.neighbors.lock().expect("failed to acquire lock");
-        cur.clear();
-        cur.extend(neighbors);
+        if let Err(e) = y.await.expect("failed to recieve msg") {
+            error!("failed to prune clock: {}", e);
+        }
     }
 
-    async fn is_outdated(&self, incoming: &Clock, tx: &mut mpsc::Sender<DBMessage>) -> bool {
+    // how our clock relates to a peer's, for the hosts we know about
+    async fn compare_clock(
+        &self,
+        incoming: &Clock,
+        tx: &mut mpsc::Sender<DBMessage>,
+    ) -> ClockOrder {
+        let mut incoming = incoming.clone();
+        self.retain_known_hosts(&mut incoming);
         let clock = self.get_clock(tx).await;
-        is_outdated(&clock, incoming)
+        compare_clocks(&clock, &incoming)
     }
 
     async fn update_values(
         &self,
-        incoming_updates: &Vec<(ClipboardEntry, String)>,
+        incoming_updates: &Vec<ClipboardRecord>,
         incoming_clock: &Clock,
         tx: &mut mpsc::Sender<DBMessage>,
-    ) {
+    ) -> usize {
+        let mut stored = 0;
         for update in incoming_updates {
-            let (entry, timestamp) = update;
-            let timestamp = Ulid::from_string(&timestamp).expect("failed to parse ulid");
-            let (x, y) = oneshot::channel();
-            let msg = match entry {
-                ClipboardEntry::Image(i) => {
-                    let i = (*i).clone();
-                    let i = i.into();
-                    DBMessage {
-                        cmd: crate::db::DBCommand::CopyData {
-                            data: ClipboardEntry::Image(i),
-                            timestamp,
-                            local: false,
-                        },
-                        sender: x,
-                    }
+            let ClipboardRecord {
+                key,
+                entry,
+                origin,
+                mime,
+                expires_at,
+                deleted,
+            } = update;
+            let timestamp = Ulid::from_string(key).expect("failed to parse ulid");
+            let cmd = match origin {
+                // deleted there, so it goes here too. tombstones are not counted
+                Some(origin) if *deleted => crate::db::DBCommand::Delete {
+                    key: timestamp,
+                    origin: Some(origin.clone()),
+                },
+                None if *deleted => {
+                    warn!("ignoring tombstone {} without origin", key);
+                    continue;
                 }
-                ClipboardEntry::Text(t) => DBMessage {
-                    cmd: crate::db::DBCommand::CopyData {
-                        data: ClipboardEntry::Text(t.clone()),
-                        timestamp,
-                        local: false,
-                    },
-                    sender: x,
+                _ => crate::db::DBCommand::CopyData {
+                    data: entry.clone(),
+                    timestamp,
+                    local: false,
+                    origin: origin.clone(),
+                    mime: mime.clone(),
+                    expires_at: *expires_at,
                 },
             };
-            tx.send(msg).await.expect("couldnt send msg");
-            let _ = y.await.expect("failed to read response");
-        }
-
-        let mut updating_clock = self.get_clock(tx).await;
-        println!("READING THE OLD CLOCK AS {:?}", updating_clock);
-        for (key, value) in incoming_clock {
-            let new_value = match updating_clock.get(key) {
-                Some(old_value) => {
-                    if old_value > value {
-                        *old_value
-                    } else {
-                        *value
+            let (x, y) = oneshot::channel();
+            tx.send(DBMessage { cmd, sender: x })
+                .await
+                .expect("couldnt send msg");
+            // entries we already have are rejected by the db, that is fine
+            if let Ok(crate::db::Response::Saved { .. }) = y.await.expect("failed to read response")
+            {
+                stored += 1;
+            }
+        }
+
+        self.merge_into_clock(incoming_clock.clone(), tx).await;
+        stored
+    }
+
+    // merges a peer's clock into ours, for the hosts we know about
+    async fn merge_into_clock(&self, mut incoming: Clock, tx: &mut mpsc::Sender<DBMessage>) {
+        self.retain_known_hosts(&mut incoming);
+        let mut clock = self.get_clock(tx).await;
+        trace!("old clock {:?}", clock);
+        merge_clock(&mut clock, &incoming);
+        trace!("new clock {:?}", clock);
+        self.save_clock(clock, tx).await;
+    }
+
+    // gossip from peers only moves the counter of the host an entry or
+    // tombstone came from, and only when it is the next one. a counter means
+    // we hold everything up to it, so anything missed in between is still
+    // pulled by anti entropy
+    async fn advance_clock(&self, origin: &Origin, tx: &mut mpsc::Sender<DBMessage>) {
+        let hosts = self.known_hosts();
+        if !hosts.is_empty() && !hosts.contains(&origin.host) {
+            return;
+        }
+        let mut clock = self.get_clock(tx).await;
+        if !advance_counter(&mut clock, origin) {
+            trace!("not advancing {} to {}", origin.host, origin.counter);
+            return;
+        }
+        self.save_clock(clock, tx).await;
+    }
+
+    // pulls what peers have and we do not, returning how many entries came
+    // from each. a dry run only compares clocks, and returns roughly how many
+    // entries each peer that is ahead would give us
+    async fn anti_entropy(
+        &self,
+        dry_run: bool,
+        tx: &mut mpsc::Sender<DBMessage>,
+    ) -> Result<Vec<(String, usize)>, String> {
+        // anti entropy is rare and prunes the clock by this list, so get it fresh
+        self.reload_neighbors(true, tx).await;
+        if !dry_run {
+            self.prune_clock(tx).await;
+        }
+        // we take a snapshot of the neighbors, rather than holding the lock
+        let neighbors = {
+            let n = self.neighbors.lock().expect("failed to acquire lock");
+            n.peers.clone()
+        };
+
+        // a dry run only looks, a peer that fails it is not backed off
+        let peers = if dry_run {
+            self.peers.detached()
+        } else {
+            self.peers.clone()
+        };
+
+        let mut pulled = Vec::new();
+        let online = neighbors
+            .iter()
+            .filter(|n| n.Online && !peer_ips(n).is_empty())
+            .count();
+        if online == 0 {
+            return Err("no peers online".to_string());
+        }
+        let mut reached = 0;
+        let mut skipped = 0;
+
+        for n in neighbors.iter() {
+            // no point in pinging if they are offline anyway
+            if !n.Online {
+                continue;
+            }
+            if peer_ips(n).is_empty() {
+                debug!("skipping {}, it has no usable address", n.HostName);
+                continue;
+            }
+            if peers.backing_off(n) {
+                debug!("skipping {}, it failed lately", n.HostName);
+                skipped += 1;
+                continue;
+            }
+            if !peers.probe(n).await {
+                warn!("skipping {}, slate is not answering there", n.HostName);
+                continue;
+            }
+            let request = peers.send(n, "clock", |client, url| client.get(url));
+            let incoming_clock = match request.await {
+                Ok(response) => match response.json::<Clock>().await {
+                    Ok(clock) => clock,
+                    Err(e) => {
+                        warn!("Failed to parse clock from {}: {}", n.HostName, e);
+                        continue;
                     }
+                },
+                Err(e) => {
+                    warn!("Failed to fetch the clock of {}: {}", n.HostName, e);
+                    continue;
                 }
-                None => *value,
             };
-            let _ = updating_clock.insert(key.clone(), new_value);
+            reached += 1;
+
+            let order = self.compare_clock(&incoming_clock, tx).await;
+            if order == ClockOrder::Concurrent {
+                // both sides copied something the other lacks, we pull theirs
+                // and they pull ours on their next round
+                debug!("clock of {} is concurrent with ours", n.HostName);
+            }
+            if dry_run {
+                let mut incoming_clock = incoming_clock;
+                self.retain_known_hosts(&mut incoming_clock);
+                let ahead = counters_ahead(&self.get_clock(tx).await, &incoming_clock);
+                if ahead > 0 {
+                    pulled.push((n.HostName.clone(), ahead as usize));
+                }
+                continue;
+            }
+            // the incoming clock has seen entries we have not
+            if matches!(order, ClockOrder::Less | ClockOrder::Concurrent) {
+                // we must update our entries first, THEN our keys
+                let clock = self.get_clock(tx).await;
+                let (request, asked) = if clock.contains_key(&n.HostName) {
+                    // only ask for what we are missing
+                    let clock = serde_json::to_string(&clock).expect("failed to serialize clock");
+                    let request = self
+                        .peers
+                        .send(n, "since", |client, url| {
+                            client.get(url).query(&[("clock", &clock)])
+                        })
+                        .await;
+                    (request, format!("since {}", clock))
+                } else {
+                    // never synced with this peer, pull everything recent
+                    let request = self
+                        .peers
+                        .send(n, "recent_clipboard", |client, url| client.get(url))
+                        .await;
+                    (request, "recent_clipboard".to_string())
+                };
+                let incoming_updates = match request {
+                    Ok(response) => match response
+                        .json::<Records>()
+                        .await
+                        .map_err(|e| e.to_string())
+                        .and_then(|records| records.open(self.cipher.as_ref(), asked.as_bytes()))
+                    {
+                        Ok(updates) => updates,
+                        Err(e) => {
+                            warn!("Failed to parse updates from {}: {}", n.HostName, e);
+                            continue;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Failed to fetch updates from {}: {}", n.HostName, e);
+                        continue;
+                    }
+                };
+
+                let stored = self
+                    .update_values(&incoming_updates, &incoming_clock, tx)
+                    .await;
+                if stored > 0 {
+                    pulled.push((n.HostName.clone(), stored));
+                }
+            }
+        }
+
+        // files do not go through the clock, so every peer is asked for its list
+        let peers = neighbors
+            .iter()
+            .filter(|n| n.Online && !peer_ips(n).is_empty() && !self.peers.backing_off(n))
+            .cloned()
+            .collect();
+        if !dry_run {
+            self.files.spawn_pull(peers, tx.clone());
         }
-        println!("SAVING THE NEW CLOCK AS {:?}", updating_clock);
-        self.save_clock(updating_clock, tx).await;
+
+        if reached == 0 && skipped == online {
+            return Err(format!(
+                "all {} online peers failed lately, they are retried later",
+                online
+            ));
+        }
+        if reached == 0 {
+            return Err(format!("could not reach any of {} online peers", online));
+        }
+        Ok(pulled)
     }
 
     pub async fn listen(&self, mut rx: Receiver<ControlMessage>, mut tx: mpsc::Sender<DBMessage>) {
-        println!("control plane started!");
+        info!("control plane started!");
 
         // init row, if needed
         {
@@ -251,135 +1068,230 @@ impl Node {
             let status = y.await.expect("failed to recieve msg");
             status.expect("did not create self row?");
         }
+        self.load_neighbors(&mut tx).await;
 
+        // replies are dropped when the asker is gone, e.g. an http request
+        // from a peer that timed out while we were busy
         while let Some(msg) = rx.recv().await {
-            println!("recieved command: {:?}", msg.cmd);
+            debug!("recieved command: {:?}", msg.cmd);
             match msg.cmd {
-                ControlCommand::AntiEntropy => {
-                    self.reload_neighbors().await;
-                    // we take a snapshot of the neighbors, rather than holding the lock
-                    let neighbors = {
-                        let n = self.neighbors.lock().expect("failed to acquire lock");
-                        n.clone()
-                    };
-
-                    let client = reqwest::Client::new();
-
-                    for i in 0..neighbors.len() {
-                        // no point in pinging if they are offline anyway
-                        if !neighbors[i].Online {
-                            continue;
-                        }
-                        let ip = neighbors[i].TailscaleIPs[0].clone();
-                        let endpoint = format!("http://{}:{}/clock", ip, PORT);
-                        let incoming_clock = match client.get(&endpoint).send().await {
-                            Ok(response) => match response.json::<Clock>().await {
-                                Ok(clock) => clock,
-                                Err(e) => {
-                                    eprintln!("Failed to parse JSON from {}: {}", endpoint, e);
-                                    continue;
-                                }
-                            },
-                            Err(e) => {
-                                eprintln!("Failed to send request to {}: {}", endpoint, e);
-                                continue;
-                            }
-                        };
-
-                        // the incoming clock is newer
-                        if self.is_outdated(&incoming_clock, &mut tx).await {
-                            // we must update our entries first, THEN our keys
-                            let endpoint = format!("http://{}:{}/recent_clipboard", ip, PORT);
-                            let incoming_updates = client
-                                .get(endpoint)
-                                .send()
-                                .await
-                                .expect("failed to send message")
-                                .json()
-                                .await
-                                .expect("failed to parse json");
-
-                            self.update_values(&incoming_updates, &incoming_clock, &mut tx)
-                                .await;
-                        }
+                ControlCommand::AntiEntropy { dry_run: true } => {
+                    let synced = self.anti_entropy(true, &mut tx).await;
+                    let _ = msg
+                        .sender
+                        .send(synced.map(|pulled| Response::Synced { pulled }));
+                }
+                ControlCommand::AntiEntropy { dry_run: false } => {
+                    let synced = self.anti_entropy(false, &mut tx).await;
+                    Metrics::add(&self.metrics.anti_entropy_rounds, 1);
+                    if let Ok(pulled) = &synced {
+                        let entries = pulled.iter().map(|(_, n)| *n as u64).sum();
+                        Metrics::add(&self.metrics.entries_pulled, entries);
                     }
-                    msg.sender.send(Ok(Response::OK)).expect("failed to reply");
+                    let _ = msg
+                        .sender
+                        .send(synced.map(|pulled| Response::Synced { pulled }));
                 }
                 ControlCommand::GetNeighbors => {
-                    self.reload_neighbors().await;
+                    self.reload_neighbors(true, &mut tx).await;
                     let info = {
                         let n = self.neighbors.lock().expect("failed to acquire lock");
-                        n.clone()
+                        n.peers.clone()
                     };
-                    msg.sender
-                        .send(Ok(Response::Neighbors { info }))
-                        .expect("failed to reply");
+                    let health = self.peers.health();
+                    let _ = msg.sender.send(Ok(Response::Neighbors { info, health }));
+                }
+                ControlCommand::Status => {
+                    self.reload_neighbors(false, &mut tx).await;
+                    let neighbors = self
+                        .neighbors
+                        .lock()
+                        .expect("failed to acquire lock")
+                        .peers
+                        .len();
+                    let _ = msg.sender.send(Ok(Response::Status {
+                        started_at: self.started_at,
+                        neighbors,
+                        port: self.peers.port,
+                    }));
                 }
                 ControlCommand::GetClock => {
-                    let data = self.get_clock(&mut tx).await;
-                    msg.sender
-                        .send(Ok(Response::Clock { data }))
-                        .expect("failed to reply");
+                    let clock = self.get_clock(&mut tx).await;
+                    let _ = msg.sender.send(Ok(Response::Clock {
+                        clock,
+                        host_name: self.host_name.clone(),
+                    }));
                 }
-                ControlCommand::Transmit { data, ttl, clock } => {
-                    let successfully_saved = {
+                ControlCommand::Transmit {
+                    source,
+                    data,
+                    ttl,
+                    origin,
+                    mime,
+                    key,
+                    expires_at,
+                } => {
+                    let local = source == Source::Local;
+                    if !local && origin.as_ref().is_some_and(|o| o.host == self.host_name) {
+                        // our own entry coming back around
+                        let _ = msg.sender.send(Ok(Response::OK));
+                        continue;
+                    }
+                    // gossip from peers that do not send a key gets a fresh one
+                    let key = key.unwrap_or_else(Ulid::new);
+                    if !self.mark_seen(key) {
+                        debug!("already handled {}, dropping it", key);
+                        let _ = msg.sender.send(Ok(Response::OK));
+                        continue;
+                    }
+                    let saved = {
                         let (x, y) = oneshot::channel();
                         let msg = DBMessage {
                             cmd: crate::db::DBCommand::CopyData {
                                 data: data.clone(),
-                                timestamp: Ulid::new(),
-                                local: clock.is_none(),
+                                timestamp: key,
+                                local,
+                                origin: origin.clone(),
+                                mime: mime.clone(),
+                                expires_at,
                             },
                             sender: x,
                         };
                         tx.send(msg).await.expect("failed to msg db");
-                        let resp = y.await.expect("failed to read response");
-                        resp.is_ok()
+                        y.await.expect("failed to read response")
                     };
 
-                    if successfully_saved {
-
-                        if clock.is_some() {
-                            self.save_clock(clock.unwrap(), &mut tx);
+                    match saved {
+                        // expired or deleted, so it goes no further
+                        Ok(crate::db::Response::Skipped) => {
+                            let _ = msg.sender.send(Ok(Response::OK));
+                        }
+                        // what was stored, for a local copy that is our own
+                        // next counter
+                        Ok(crate::db::Response::Saved { origin }) => {
+                            if let (false, Some(origin)) = (local, &origin) {
+                                self.advance_clock(origin, &mut tx).await;
+                            }
+                            let ttl = ttl.unwrap_or(self.ttl);
+                            self.gossip(key, data, mime, origin, expires_at, false, ttl, &mut tx)
+                                .await;
+                            let _ = msg.sender.send(Ok(Response::OK));
+                        }
+                        _ => {
+                            self.forget_seen(key);
+                            let _ = msg.sender.send(Err("failed to save".into()));
+                        }
+                    }
+                }
+                ControlCommand::Delete {
+                    key,
+                    ttl,
+                    source,
+                    origin,
+                } => {
+                    let local = source == Source::Local;
+                    if !local && origin.as_ref().is_some_and(|o| o.host == self.host_name) {
+                        // our own delete coming back around
+                        let _ = msg.sender.send(Ok(Response::OK));
+                        continue;
+                    }
+                    let deleted = {
+                        let (x, y) = oneshot::channel();
+                        let msg = DBMessage {
+                            cmd: crate::db::DBCommand::Delete { key, origin },
+                            sender: x,
                         };
+                        tx.send(msg).await.expect("failed to msg db");
+                        y.await.expect("failed to read response")
+                    };
 
-                        let ttl = match ttl {
-                            Some(x) => x,
-                            None => TTL,
-                        };
-                        self.gossip(data, MAX_PER_ROUND, ttl, &mut tx).await;
-                        msg.sender.send(Ok(Response::OK)).expect("failed to reply");
-                    } else {
-                        msg.sender
-                            .send(Err("failed to save".into()))
-                            .expect("failed to reply");
+                    match deleted {
+                        Ok(crate::db::Response::Deleted {
+                            origin: Some(origin),
+                        }) => {
+                            if !local {
+                                self.advance_clock(&origin, &mut tx).await;
+                            }
+                            let ttl = ttl.unwrap_or(self.ttl);
+                            let entry = ClipboardEntry::Text(String::new());
+                            self.gossip(key, entry, None, Some(origin), None, true, ttl, &mut tx)
+                                .await;
+                            let _ = msg.sender.send(Ok(Response::OK));
+                        }
+                        // deleted before, it was passed on back then
+                        Ok(_) => {
+                            let _ = msg.sender.send(Ok(Response::OK));
+                        }
+                        Err(e) => {
+                            let _ = msg.sender.send(Err(e));
+                        }
                     }
                 }
-                _ => {
-                    msg.sender.send(Ok(Response::OK)).expect("failed to reply");
-                }
             }
         }
     }
 }
 
+// where an entry or a delete handed to the control plane comes from. only a
+// local one moves our own counter, one from a peer keeps the origin it has
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Local,
+    Peer,
+}
+
+// each command is moved through the channel once, its size does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum ControlCommand {
-    AntiEntropy,
+    // a dry run pulls nothing, it only says what it would
+    AntiEntropy {
+        dry_run: bool,
+    },
     Transmit {
+        source: Source,
         data: ClipboardEntry,
         ttl: Option<u64>,
-        clock: Option<Clock>
+        // None for a local copy, which takes our own next counter
+        origin: Option<Origin>,
+        mime: Option<String>,
+        // ulid of the entry, None for a local copy
+        key: Option<Ulid>,
+        expires_at: Option<u64>,
+    },
+    // deletes an entry everywhere, origin is None for a local delete
+    Delete {
+        key: Ulid,
+        ttl: Option<u64>,
+        source: Source,
+        origin: Option<Origin>,
     },
     GetNeighbors,
+    Status,
     GetClock,
 }
 
 #[derive(Debug)]
 pub enum Response {
     OK,
-    Neighbors { info: Vec<PeerInfo> },
-    Clock { data: Clock },
+    Neighbors {
+        info: Vec<PeerInfo>,
+        health: HashMap<String, PeerHealth>,
+    },
+    Status {
+        started_at: SystemTime,
+        neighbors: usize,
+        port: u16,
+    },
+    // entries stored per peer during an anti entropy round
+    Synced {
+        pulled: Vec<(String, usize)>,
+    },
+    // host_name is this node's row in the clock
+    Clock {
+        clock: Clock,
+        host_name: String,
+    },
 }
 
 #[derive(Debug)]
@@ -388,26 +1300,160 @@ pub struct ControlMessage {
     pub sender: oneshot::Sender<Result<Response, String>>,
 }
 
-pub async fn trigger_anti_entropy(tx: mpsc::Sender<ControlMessage>) {
-    println!("anti entropy trigger started!");
-    let duration = Duration::from_millis(ANTI_ENTROPY_TIMEOUT_MS);
+// the interval give or take 10%, so nodes started together drift apart
+// instead of all hitting each other in the same moment
+fn jittered(interval_ms: u64) -> Duration {
+    let spread = interval_ms / 10;
+    Duration::from_millis(rand::random_range(
+        interval_ms - spread..=interval_ms + spread,
+    ))
+}
+
+pub async fn trigger_anti_entropy(tx: mpsc::Sender<ControlMessage>, interval_ms: u64) {
+    info!("anti entropy trigger started!");
     loop {
-        println!("anti entropy trigger!");
+        debug!("triggering anti entropy");
         let (x, y) = oneshot::channel();
         let msg = ControlMessage {
-            cmd: ControlCommand::AntiEntropy,
+            cmd: ControlCommand::AntiEntropy { dry_run: false },
             sender: x,
         };
         tx.send(msg).await.expect("failed to send message");
         let response = y.await.expect("failed to read response");
         match response {
             Ok(s) => {
-                println!("{:?}", s);
+                debug!("anti entropy finished: {:?}", s);
             }
             Err(e) => {
-                println!("{:?}", e);
+                warn!("anti entropy failed: {}", e);
             }
         }
-        sleep(duration).await;
+        sleep(jittered(interval_ms)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(counters: &[(&str, u64)]) -> Clock {
+        counters
+            .iter()
+            .map(|&(host, counter)| (host.to_string(), counter))
+            .collect()
+    }
+
+    fn origin(host: &str, counter: u64) -> Origin {
+        Origin {
+            host: host.to_string(),
+            counter,
+        }
+    }
+
+    #[test]
+    fn advance_counter_takes_only_the_next_one() {
+        let mut local = clock(&[("a", 3)]);
+        assert!(advance_counter(&mut local, &origin("a", 4)));
+        assert_eq!(local, clock(&[("a", 4)]));
+
+        // a gap is left for anti entropy, an old counter changes nothing
+        assert!(!advance_counter(&mut local, &origin("a", 6)));
+        assert!(!advance_counter(&mut local, &origin("a", 2)));
+        assert!(!advance_counter(&mut local, &origin("a", 4)));
+        assert_eq!(local, clock(&[("a", 4)]));
+    }
+
+    #[test]
+    fn advance_counter_starts_new_hosts_at_one() {
+        let mut local = clock(&[("a", 3)]);
+        assert!(!advance_counter(&mut local, &origin("b", 2)));
+        assert!(advance_counter(&mut local, &origin("b", 1)));
+        assert_eq!(local, clock(&[("a", 3), ("b", 1)]));
+    }
+    #[test]
+    fn seen_drops_repeats_within_the_window() {
+        let mut seen = Seen::default();
+        let now = Instant::now();
+        let key = Ulid::new();
+        assert!(seen.mark(key, now));
+        assert!(!seen.mark(key, now + SEEN_WINDOW));
+        assert!(seen.mark(Ulid::new(), now));
+        // once the window is over the key is taken again
+        assert!(seen.mark(key, now + SEEN_WINDOW * 2 + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn seen_forgets_the_oldest_when_full() {
+        let mut seen = Seen::default();
+        let now = Instant::now();
+        let first = Ulid::new();
+        assert!(seen.mark(first, now));
+        for _ in 1..SEEN_CAPACITY {
+            assert!(seen.mark(Ulid::new(), now));
+        }
+        assert!(!seen.mark(first, now));
+        assert!(seen.mark(Ulid::new(), now));
+        assert!(seen.mark(first, now));
+    }
+
+    #[test]
+    fn seen_forget_lets_a_key_through_again() {
+        let mut seen = Seen::default();
+        let now = Instant::now();
+        let key = Ulid::new();
+        assert!(seen.mark(key, now));
+        seen.forget(key);
+        assert!(seen.mark(key, now));
+    }
+    #[test]
+    fn merge_clock_takes_the_higher_counter() {
+        let mut local = clock(&[("a", 5), ("b", 2)]);
+        merge_clock(&mut local, &clock(&[("a", 3), ("b", 4), ("c", 1)]));
+        assert_eq!(local, clock(&[("a", 5), ("b", 4), ("c", 1)]));
+    }
+
+    #[test]
+    fn merge_clock_never_moves_back() {
+        let mut local = clock(&[("a", 5)]);
+        merge_clock(&mut local, &clock(&[("a", 0)]));
+        merge_clock(&mut local, &Clock::new());
+        assert_eq!(local, clock(&[("a", 5)]));
+    }
+    #[test]
+    fn compare_clocks_orders_clocks() {
+        let a = clock(&[("a", 2), ("b", 1)]);
+        assert_eq!(compare_clocks(&a, &a.clone()), ClockOrder::Equal);
+        assert_eq!(
+            compare_clocks(&a, &clock(&[("a", 3), ("b", 1)])),
+            ClockOrder::Less
+        );
+        assert_eq!(
+            compare_clocks(&clock(&[("a", 3), ("b", 1)]), &a),
+            ClockOrder::Greater
+        );
+    }
+
+    #[test]
+    fn compare_clocks_tells_concurrent_apart() {
+        let a = clock(&[("a", 3), ("b", 1)]);
+        let b = clock(&[("a", 2), ("b", 2)]);
+        assert_eq!(compare_clocks(&a, &b), ClockOrder::Concurrent);
+        assert_eq!(compare_clocks(&b, &a), ClockOrder::Concurrent);
+        assert!(is_outdated(&a, &b));
+        assert!(is_outdated(&b, &a));
+    }
+
+    #[test]
+    fn compare_clocks_counts_missing_hosts_as_zero() {
+        let a = clock(&[("a", 1)]);
+        assert_eq!(
+            compare_clocks(&a, &clock(&[("a", 1), ("b", 0)])),
+            ClockOrder::Equal
+        );
+        assert_eq!(
+            compare_clocks(&a, &clock(&[("a", 1), ("b", 1)])),
+            ClockOrder::Less
+        );
+        assert_eq!(compare_clocks(&a, &Clock::new()), ClockOrder::Greater);
     }
 }
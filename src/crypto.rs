@@ -0,0 +1,280 @@
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{self, Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fs;
+use tracing::warn;
+
+use crate::config::config_dir;
+use crate::db::{ClipboardEntry, ClipboardRecord};
+
+// pre-shared key used to seal clipboard entries between peers
+pub type Cipher = XChaCha20Poly1305;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Sealed {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+// what actually travels in a gossip message
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Payload {
+    Plain(ClipboardEntry),
+    Sealed(Sealed),
+}
+
+// `metadata` is everything sent along with the entry. it is not encrypted
+// but sealed with it, so none of it can be changed or replayed with another
+// entry without the payload failing to open
+impl Payload {
+    pub fn new(entry: ClipboardEntry, cipher: Option<&Cipher>, metadata: &[u8]) -> Self {
+        match cipher {
+            Some(cipher) => Payload::Sealed(seal(cipher, &entry, metadata)),
+            None => Payload::Plain(entry),
+        }
+    }
+
+    // with a key configured we only accept sealed payloads, otherwise anyone
+    // could skip the encryption and inject plain entries
+    pub fn open(self, cipher: Option<&Cipher>, metadata: &[u8]) -> Result<ClipboardEntry, String> {
+        match (self, cipher) {
+            (Payload::Sealed(sealed), Some(cipher)) => open(cipher, &sealed, metadata),
+            (Payload::Plain(entry), None) => Ok(entry),
+            (Payload::Plain(_), Some(_)) => Err("refusing unencrypted entry".to_string()),
+            (Payload::Sealed(_), None) => Err("got encrypted entry but no key is set".to_string()),
+        }
+    }
+}
+
+// what a peer answers anti entropy with. a plain list without a key, as it
+// always was, and sealed like gossip with one
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Records {
+    Plain(Vec<ClipboardRecord>),
+    Sealed(Sealed),
+}
+
+impl Records {
+    // `request` says what was asked for, so an answer cannot be passed off
+    // as one to another request
+    pub fn new(records: Vec<ClipboardRecord>, cipher: Option<&Cipher>, request: &[u8]) -> Self {
+        match cipher {
+            Some(cipher) => Records::Sealed(seal(cipher, &records, request)),
+            None => Records::Plain(records),
+        }
+    }
+
+    pub fn open(
+        self,
+        cipher: Option<&Cipher>,
+        request: &[u8],
+    ) -> Result<Vec<ClipboardRecord>, String> {
+        match (self, cipher) {
+            (Records::Sealed(sealed), Some(cipher)) => open(cipher, &sealed, request),
+            (Records::Plain(records), None) => Ok(records),
+            (Records::Plain(_), Some(_)) => Err("refusing unencrypted entries".to_string()),
+            (Records::Sealed(_), None) => {
+                Err("got encrypted entries but no key is set".to_string())
+            }
+        }
+    }
+}
+
+pub fn seal<T: Serialize>(cipher: &Cipher, value: &T, metadata: &[u8]) -> Sealed {
+    let plaintext = serde_json::to_vec(value).expect("failed to serialize entry");
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = aead::Payload {
+        msg: &plaintext,
+        aad: metadata,
+    };
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .expect("failed to encrypt entry");
+
+    Sealed {
+        nonce: nonce.to_vec(),
+        ciphertext,
+    }
+}
+
+pub fn open<T: DeserializeOwned>(
+    cipher: &Cipher,
+    sealed: &Sealed,
+    metadata: &[u8],
+) -> Result<T, String> {
+    if sealed.nonce.len() != 24 {
+        return Err("invalid nonce".to_string());
+    }
+    let nonce = XNonce::from_slice(&sealed.nonce);
+    let payload = aead::Payload {
+        msg: &sealed.ciphertext,
+        aad: metadata,
+    };
+    let plaintext = cipher
+        .decrypt(nonce, payload)
+        .map_err(|_| "entry failed authentication".to_string())?;
+
+    serde_json::from_slice(&plaintext).map_err(|e| e.to_string())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// 32 byte key as hex (e.g. from `openssl rand -hex 32`), the same on every
+// device. taken from SLATE_KEY, or the file at SLATE_KEY_FILE (default
+// ~/.config/slate/key). without one, gossip is sent in plain text
+pub fn load_key() -> Option<Cipher> {
+    let hex = match std::env::var("SLATE_KEY") {
+        Ok(key) => key,
+        Err(_) => {
+            let path = match std::env::var("SLATE_KEY_FILE") {
+                Ok(path) => path,
+                Err(_) => format!("{}/key", config_dir()?),
+            };
+            fs::read_to_string(path).ok()?
+        }
+    };
+
+    match decode_hex(hex.trim()) {
+        Some(key) if key.len() == 32 => Some(XChaCha20Poly1305::new_from_slice(&key).ok()?),
+        _ => {
+            warn!("encryption key must be 32 bytes of hex, ignoring it");
+            None
+        }
+    }
+}
+
+// the passphrase for encrypting the db at rest, from SLATE_PASSPHRASE.
+// without one, clipboard entries and files are stored as they are
+pub fn load_passphrase() -> Option<String> {
+    std::env::var("SLATE_PASSPHRASE")
+        .ok()
+        .filter(|p| !p.is_empty())
+}
+
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Cipher, String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("failed to derive key: {}", e))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+pub fn new_nonce() -> Vec<u8> {
+    XChaCha20Poly1305::generate_nonce(&mut OsRng).to_vec()
+}
+
+// a row holds several encrypted columns under one stored nonce, so each
+// column gets its own by mixing in `column`
+fn column_nonce(nonce: &[u8], column: u8) -> Result<XNonce, String> {
+    if nonce.len() != 24 {
+        return Err("invalid nonce".to_string());
+    }
+    let mut nonce = *XNonce::from_slice(nonce);
+    nonce[23] ^= column;
+    Ok(nonce)
+}
+
+pub fn encrypt_column(cipher: &Cipher, nonce: &[u8], column: u8, data: &[u8]) -> Vec<u8> {
+    let nonce = column_nonce(nonce, column).expect("failed to build nonce");
+    cipher
+        .encrypt(&nonce, data)
+        .expect("failed to encrypt column")
+}
+
+pub fn decrypt_column(
+    cipher: &Cipher,
+    nonce: &[u8],
+    column: u8,
+    data: &[u8],
+) -> Result<Vec<u8>, String> {
+    let nonce = column_nonce(nonce, column)?;
+    cipher
+        .decrypt(&nonce, data)
+        .map_err(|_| "stored data failed authentication".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> Cipher {
+        XChaCha20Poly1305::new(&[7u8; 32].into())
+    }
+
+    fn text(entry: ClipboardEntry) -> String {
+        match entry {
+            ClipboardEntry::Text(t) => t,
+            _ => panic!("expected text"),
+        }
+    }
+
+    #[test]
+    fn sealed_entry_opens() {
+        let sealed = Payload::new(
+            ClipboardEntry::Text("hunter2".into()),
+            Some(&cipher()),
+            b"meta",
+        );
+        assert!(matches!(sealed, Payload::Sealed(_)));
+        let entry = sealed.open(Some(&cipher()), b"meta").unwrap();
+        assert_eq!(text(entry), "hunter2");
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let mut sealed = seal(&cipher(), &ClipboardEntry::Text("hunter2".into()), b"meta");
+        sealed.ciphertext[0] ^= 1;
+        assert!(open::<ClipboardEntry>(&cipher(), &sealed, b"meta").is_err());
+    }
+
+    #[test]
+    fn changed_metadata_is_rejected() {
+        let sealed = Payload::new(
+            ClipboardEntry::Text("hunter2".into()),
+            Some(&cipher()),
+            b"meta",
+        );
+        assert!(sealed.open(Some(&cipher()), b"deleted").is_err());
+    }
+
+    #[test]
+    fn other_key_is_rejected() {
+        let sealed = seal(&cipher(), &ClipboardEntry::Text("hunter2".into()), b"");
+        let other = XChaCha20Poly1305::new(&[8u8; 32].into());
+        assert!(open::<ClipboardEntry>(&other, &sealed, b"").is_err());
+    }
+
+    #[test]
+    fn plain_entry_is_refused_with_a_key() {
+        let plain = Payload::new(ClipboardEntry::Text("hunter2".into()), None, b"");
+        assert!(plain.open(Some(&cipher()), b"").is_err());
+    }
+
+    #[test]
+    fn records_are_bound_to_the_request() {
+        let sealed = Records::new(Vec::new(), Some(&cipher()), b"since {}");
+        let json = serde_json::to_string(&sealed).unwrap();
+        let back: Records = serde_json::from_str(&json).unwrap();
+        assert!(back.open(Some(&cipher()), b"recent_clipboard").is_err());
+        let back: Records = serde_json::from_str(&json).unwrap();
+        assert!(back.open(Some(&cipher()), b"since {}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn plain_records_stay_a_list() {
+        let json = serde_json::to_string(&Records::new(Vec::new(), None, b"")).unwrap();
+        assert_eq!(json, "[]");
+    }
+}
@@ -1,324 +1,1119 @@
-use std::{fs, os::fd::AsRawFd, process::exit};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, process::exit};
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
-use tokio::{
-    net::{UnixListener, UnixStream},
-    task,
-};
+use tokio::task::{self, JoinSet};
+use tokio::time::sleep;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use ulid::Ulid;
 
-use arboard;
-use libc;
-
-use crate::control_plane::{trigger_anti_entropy, ControlCommand, ControlMessage, Node};
-use crate::db::{ClipboardWrapper, DBCommand, DBMessage, Database, Response};
+use crate::clipboard::{is_blank, SystemClipboard};
+use crate::config::Config;
+use crate::control_plane::{
+    trigger_anti_entropy, ControlCommand, ControlMessage, Node, PeerHealth, PeerInfo, Source,
+};
+use crate::crypto::load_key;
+use crate::db::{
+    self, format_bytes, unix_now, ClipboardEntry, Clock, DBCommand, DBMessage, Database, FileInfo,
+    Response,
+};
+use crate::discovery;
 use crate::http_server::run_http_server;
+use crate::ipc::{self, DaemonListener, Listener, Reply, Request};
+use crate::metrics::Metrics;
+use crate::platform::{self, runtime_path, Spawned};
+use crate::protocol;
 
-pub const SOCKET_PATH: &str = "/tmp/slate_daemon.sock";
-const PID_FILE: &str = "/tmp/slate_daemon.pid";
+// how often expired entries are deleted when the db is otherwise idle
+const PURGE_INTERVAL: Duration = Duration::from_secs(10);
+// how often `slate logs -f` looks for new lines
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// the pid file and log are named after the socket, so daemons on different
+// sockets do not trip over each other. the default is slate_daemon.pid
+fn instance_file(config: &Config, extension: &str) -> PathBuf {
+    let name = Path::new(&config.socket_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| "slate_daemon".to_string());
+    runtime_path(&format!("{}.{}", name, extension))
+}
+
+fn pid_file(config: &Config) -> PathBuf {
+    instance_file(config, "pid")
+}
 
-pub fn start_daemon() -> Result<(), String> {
-    if let Ok(_) = fs::metadata(PID_FILE) {
+fn log_file(config: &Config) -> PathBuf {
+    instance_file(config, "log")
+}
+
+// state of the clipboard watcher, shared between the watcher task and clients
+#[derive(Default)]
+struct WatchState {
+    enabled: bool,
+    // hash of the last clipboard content we stored or set ourselves
+    last_hash: Option<u64>,
+}
+
+type SharedWatch = Arc<Mutex<WatchState>>;
+
+pub fn start_daemon(config: Config) -> Result<(), String> {
+    if fs::metadata(pid_file(&config)).is_ok() {
         eprintln!("slate daemon is already running!");
         exit(1);
     }
 
-    // fork proc
-    match unsafe { libc::fork() } {
-        -1 => Err(format!("failed to fork process to start daemon")),
-        0 => {
-            let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();
-            if let Err(e) = rt.block_on(run_daemon()) {
-                Err(format!("daemon error: {}", e))
-            } else {
-                Ok(())
-            }
-        }
-        _ => Ok(()),
+    match platform::spawn_daemon(&log_file(&config))? {
+        Spawned::Parent => Ok(()),
+        Spawned::Child => run_in_foreground(config),
     }
 }
 
-async fn run_daemon() -> std::io::Result<()> {
-    // output prints to a log file, easy to debug
-    let log_file = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("/tmp/slate_daemon.log")?;
-
-    let stdout = log_file.try_clone()?;
-    let stderr = log_file.try_clone()?;
-    unsafe {
-        libc::dup2(stdout.as_raw_fd(), libc::STDOUT_FILENO);
-        libc::dup2(stderr.as_raw_fd(), libc::STDERR_FILENO);
+// runs the daemon in the current process, until it is stopped
+pub fn run_in_foreground(config: Config) -> Result<(), String> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    if let Err(e) = rt.block_on(run_daemon(config)) {
+        Err(format!("daemon error: {}", e))
+    } else {
+        Ok(())
     }
+}
 
-    println!("started service");
+async fn run_daemon(config: Config) -> std::io::Result<()> {
+    let started_at = SystemTime::now();
+    let cipher = load_key();
 
-    // db task
+    info!("started service");
+
+    // without a node there is nothing to run, so fail before taking the socket
+    let discovery = discovery::from_config(&config).map_err(std::io::Error::other)?;
+    let metrics = Arc::new(Metrics::new());
+    let node = Node::new(
+        started_at,
+        &config,
+        cipher.clone(),
+        discovery.clone(),
+        metrics.clone(),
+    )
+    .await
+    .map_err(std::io::Error::other)?;
+
+    let mut tasks = Tasks::default();
+
+    // db task, opened up front so a wrong passphrase stops the daemon
+    let db = Database::new(&config).map_err(std::io::Error::other)?;
+    let reads = db.reader(&config).map_err(std::io::Error::other)?;
     let (database_tx, rx) = mpsc::channel(100);
-    task::spawn(async move {
-        let db = Database::new().expect("unable to create db");
-        db.listen(rx).await;
+    let (writer_tx, writer_rx) = mpsc::channel(100);
+    let (reader_tx, reader_rx) = mpsc::channel(100);
+    tasks.spawn_blocking("db", move || db.listen(writer_rx));
+    tasks.spawn_blocking("db reader", move || reads.listen(reader_rx));
+    tasks.spawn("db router", async move {
+        db::route(rx, writer_tx, reader_tx).await;
+    });
+
+    // deletes entries copied with a ttl once their time is up
+    let db_tx = database_tx.clone();
+    tasks.spawn("purge", async move {
+        purge_expired(db_tx).await;
     });
 
     // control plane task
     let (control_tx, rx) = mpsc::channel(100);
     let db_tx = database_tx.clone();
-    task::spawn(async move {
-        let node = Node::new().await;
+    tasks.spawn("control plane", async move {
         node.listen(rx, db_tx).await;
     });
 
     // anti entropy trigger
     let tx = control_tx.clone();
-    task::spawn(async move {
-        trigger_anti_entropy(tx).await;
+    let interval_ms = config.anti_entropy_interval_ms;
+    tasks.spawn("anti entropy", async move {
+        trigger_anti_entropy(tx, interval_ms).await;
+    });
+
+    // clipboard watcher, idle until enabled with `slate watch on`
+    let watch: SharedWatch = Arc::new(Mutex::new(WatchState::default()));
+    let watch_state = watch.clone();
+    let c_tx_watch = control_tx.clone();
+    let interval = Duration::from_millis(config.watch_interval_ms);
+    tasks.spawn("watcher", async move {
+        watch_clipboard(watch_state, c_tx_watch, interval).await;
     });
 
     // http task
     let db_tx_http = database_tx.clone();
     let c_tx_http = control_tx.clone();
-    task::spawn(async move {
-        run_http_server(db_tx_http, c_tx_http).await;
+    let http_config = config.clone();
+    tasks.spawn("http", async move {
+        let ip = discovery
+            .bind_addr()
+            .await
+            .expect("failed to pick an address to listen on");
+        run_http_server(db_tx_http, c_tx_http, ip, http_config, cipher, metrics).await;
     });
 
     // create PID file and a SOCKET file for daemon
-    fs::write(PID_FILE, std::process::id().to_string())?;
+    fs::write(pid_file(&config), std::process::id().to_string())?;
+
+    let mut listener = DaemonListener::bind(&config.socket_path)?;
+    tasks.spawn("socket", async move {
+        loop {
+            match listener.accept().await {
+                Ok(stream) => {
+                    let db_tx = database_tx.clone();
+                    let cp_tx = control_tx.clone();
+                    let watch = watch.clone();
+                    task::spawn(handle_client(stream, db_tx, cp_tx, watch));
+                }
+                Err(e) => {
+                    error!("connection failed: {}", e);
+                }
+            }
+        }
+    });
+
+    // the rest cannot do without whichever task stopped, so rather than
+    // answer nothing we go, leaving no pid file behind for `slate start`
+    let stopped = tasks.first_stopped().await;
+    error!("{}, shutting down", stopped);
+    let _ = fs::remove_file(pid_file(&config));
+    ipc::cleanup(&config.socket_path);
+    Err(std::io::Error::other(stopped))
+}
+
+// the tasks of the daemon, each meant to run as long as it does
+#[derive(Default)]
+struct Tasks {
+    set: JoinSet<()>,
+    names: HashMap<task::Id, &'static str>,
+}
 
-    if fs::metadata(SOCKET_PATH).is_ok() {
-        fs::remove_file(SOCKET_PATH)?;
+impl Tasks {
+    fn spawn(&mut self, name: &'static str, task: impl Future<Output = ()> + Send + 'static) {
+        let id = self.set.spawn(task).id();
+        self.names.insert(id, name);
     }
 
-    let listener = UnixListener::bind(SOCKET_PATH)?;
+    fn spawn_blocking(&mut self, name: &'static str, task: impl FnOnce() + Send + 'static) {
+        let id = self.set.spawn_blocking(task).id();
+        self.names.insert(id, name);
+    }
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                let db_tx = database_tx.clone();
-                let cp_tx = control_tx.clone();
-                task::spawn(handle_client(stream, db_tx, cp_tx));
-            }
-            Err(e) => {
-                eprintln!("connection failed: {}", e);
-            }
-        }
+    // waits for the first task to stop, usually by panicking, and says which
+    async fn first_stopped(&mut self) -> String {
+        let (id, how) = match self.set.join_next_with_id().await {
+            Some(Ok((id, ()))) => (id, "stopped"),
+            Some(Err(e)) if e.is_panic() => (e.id(), "panicked"),
+            Some(Err(e)) => (e.id(), "was cancelled"),
+            None => return "no tasks were started".to_string(),
+        };
+        let name = self.names.get(&id).copied().unwrap_or("unknown");
+        format!("{} task {}", name, how)
     }
 }
 
-async fn handle_client(
-    mut stream: UnixStream,
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
     tx: mpsc::Sender<DBMessage>,
     cp_tx: mpsc::Sender<ControlMessage>,
+    watch: SharedWatch,
 ) {
     let mut reader = BufReader::new(&mut stream);
-    let mut command = String::new();
+    // a line of json from an older cli, a frame from anything newer
+    let framed = match reader.fill_buf().await {
+        Ok(buf) => buf.first() != Some(&b'{'),
+        Err(e) => {
+            warn!("failed to read request: {}", e);
+            return;
+        }
+    };
+    let read = if framed {
+        match protocol::read(&mut reader).await {
+            Ok(frame) if frame.kind == protocol::REQUEST => Ok(frame.payload),
+            Ok(frame) => Err(format!("expected a request, got frame kind {}", frame.kind)),
+            Err(e) => Err(e),
+        }
+    } else {
+        let mut line = Vec::new();
+        reader
+            .read_until(b'\n', &mut line)
+            .await
+            .map(|_| line)
+            .map_err(|e| e.to_string())
+    };
+    let payload = match read {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!("failed to read request: {}", e);
+            return;
+        }
+    };
 
-    if reader.read_line(&mut command).await.is_err() {
-        eprintln!("failed to read command");
-        return;
-    }
+    let request: Request = match serde_json::from_slice(&payload) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!(
+                "invalid request {:?}: {}",
+                String::from_utf8_lossy(&payload).trim(),
+                e
+            );
+            let reply = failure(format!("invalid request: {}", e));
+            write_reply(reader.get_mut(), &reply, framed).await;
+            return;
+        }
+    };
+    debug!("got request {:?}", request);
 
-    let command = command.trim();
-    println!("got command {}", command);
+    // every log line while handling it carries the command
+    let span = info_span!("request", command = request.name());
+    let reply = handle_request(request, tx, cp_tx, watch)
+        .instrument(span)
+        .await;
+    write_reply(reader.get_mut(), &reply, framed).await;
+}
 
+async fn handle_request(
+    request: Request,
+    tx: mpsc::Sender<DBMessage>,
+    cp_tx: mpsc::Sender<ControlMessage>,
+    watch: SharedWatch,
+) -> Reply {
+    // commands with something to show set data, the rest only have a message
+    let mut data: Option<serde_json::Value> = None;
     let (x, y) = oneshot::channel();
-    let response = match command {
-        cmd if cmd.starts_with("upload ") => {
-            let args = cmd.strip_prefix("upload ").unwrap().to_string();
-            let (file_name, file_path) = args.split_once(" ").unwrap();
-
+    let text = match request {
+        Request::Upload {
+            file_name,
+            file_path,
+        } => {
             let msg = DBMessage {
                 cmd: DBCommand::Upload {
-                    file_name: file_name.to_string(),
-                    file_path: file_path.to_string(),
+                    file_name: file_name.clone(),
+                    file_path: file_path.clone(),
                     timestamp: Ulid::new(),
-                    local: true
+                    local: true,
                 },
                 sender: x,
             };
 
             if let Err(e) = tx.send(msg).await {
-                format!("unable to send msg to db {}", e)
+                return failure(format!("unable to send msg to db {}", e));
             } else {
                 let response = y.await.expect("failed to read response");
                 match response {
                     Ok(_) => format!("uploading file {} from {}\n", file_name, file_path),
-                    Err(e) => format!(
-                        "uploading file {} from {} got error {}\n",
-                        file_name, file_path, e
-                    ),
+                    Err(e) => {
+                        return failure(format!(
+                            "uploading file {} from {} got error {}\n",
+                            file_name, file_path, e
+                        ))
+                    }
                 }
             }
         }
-        cmd if cmd.starts_with("download ") => {
-            let args = cmd.strip_prefix("download ").unwrap().to_string();
-            let (file_name, file_path) = args.split_once(" ").unwrap();
-
+        Request::Download {
+            file_name,
+            path,
+            raw,
+        } => {
             let msg = DBMessage {
                 cmd: DBCommand::Download {
-                    download_path: file_path.to_string(),
-                    file_name: file_name.to_string(),
+                    download_path: path.clone(),
+                    file_name: file_name.clone(),
+                    raw,
+                },
+                sender: x,
+            };
+            if let Err(e) = tx.send(msg).await {
+                return failure(format!("unable to send msg to db {}", e));
+            } else {
+                let response = y.await.expect("failed to read response");
+                match response {
+                    Ok(Response::Downloaded { path, bytes }) => {
+                        format!(
+                            "downloaded file {} to {} ({} bytes)\n",
+                            file_name, path, bytes
+                        )
+                    }
+                    Err(e) => {
+                        return failure(format!(
+                            "downloading file {} at {} got error {}\n",
+                            file_name, path, e
+                        ))
+                    }
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
+                }
+            }
+        }
+        Request::Export { path } => {
+            let msg = DBMessage {
+                cmd: DBCommand::ExportAll { path: path.clone() },
+                sender: x,
+            };
+            if let Err(e) = tx.send(msg).await {
+                return failure(format!("unable to send msg to db {}", e));
+            } else {
+                let response = y.await.expect("failed to read response");
+                match response {
+                    Ok(Response::Exported {
+                        json: Some(export), ..
+                    }) => {
+                        data = serde_json::from_str(&export).ok();
+                        export
+                    }
+                    Ok(Response::Exported {
+                        entries,
+                        files,
+                        json: None,
+                    }) => format!(
+                        "exported {} entries and {} files to {}\n",
+                        entries,
+                        files,
+                        path.unwrap_or_default()
+                    ),
+                    Err(e) => return failure(format!("exporting got error {}\n", e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
+                }
+            }
+        }
+        Request::Import { path, overwrite } => {
+            let msg = DBMessage {
+                cmd: DBCommand::ImportAll {
+                    path: path.clone(),
+                    overwrite,
                 },
                 sender: x,
             };
             if let Err(e) = tx.send(msg).await {
-                format!("unable to send msg to db {}", e)
+                return failure(format!("unable to send msg to db {}", e));
             } else {
                 let response = y.await.expect("failed to read response");
                 match response {
-                    Ok(_) => format!("downloading file {} at {}\n", file_name, file_path),
-                    Err(e) => format!(
-                        "downloading file {} at {} got error {}\n",
-                        file_name, file_path, e
+                    Ok(Response::Imported {
+                        added,
+                        files,
+                        skipped,
+                    }) => format!(
+                        "imported {} new entries and {} files from {}, {} were already here\n",
+                        added, files, path, skipped
                     ),
+                    Err(e) => return failure(format!("importing {} got error {}\n", path, e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
                 }
             }
         }
-        "files" => {
+        Request::Files => {
             let msg = DBMessage {
                 cmd: DBCommand::ListFiles,
                 sender: x,
             };
 
             if let Err(e) = tx.send(msg).await {
-                format!("unable to send msg to db {}", e)
+                return failure(format!("unable to send msg to db {}", e));
             } else {
                 let response = y.await.expect("failed to read response");
                 match response {
-                    Ok(Response::Files { names }) => {
-                        if names.len() == 0 {
-                            "NO FILES".to_string()
+                    Ok(Response::Files { files }) => {
+                        data = Some(json!({ "files": files_json(&files) }));
+                        if files.is_empty() {
+                            "NO FILES\n".to_string()
                         } else {
-                            format!("slate_files {}\n", names.join(" "))
+                            format_files(files)
                         }
                     }
 
-                    Err(e) => format!("listing files got error {}\n", e),
-                    _ => {
-                        format!("SHOULD NEVER PRINT?!\n")
-                    }
+                    Err(e) => return failure(format!("listing files got error {}\n", e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
                 }
             }
         }
-        "copy" => {
-            println!("got msg copy");
-            let mut clipboard = arboard::Clipboard::new().expect("unable to open clipboard");
-
-            let data = {
-                if let Ok(text) = clipboard.get_text() {
-                    Some(crate::db::ClipboardEntry::Text(text))
-                } else if let Ok(image) = clipboard.get_image() {
-                    Some(crate::db::ClipboardEntry::Image(image.into()))
-                } else if let Ok(text) = fallback_get_clipboard_hyprland() {
-                    Some(crate::db::ClipboardEntry::Text(text))
-                } else {
-                    eprintln!("failed to get text: {}", clipboard.get_text().unwrap_err());
-                    None
+        Request::Copy {
+            ttl_secs,
+            entry,
+            primary,
+        } => {
+            debug!("got msg copy");
+            let read = match entry {
+                Some(entry) => {
+                    let mime = match entry {
+                        ClipboardEntry::Image(_) => "image/png",
+                        _ => "text/plain",
+                    };
+                    Ok(Some((entry, mime.to_string())).filter(|(e, _)| !is_blank(e)))
                 }
+                None if primary => SystemClipboard::open_selection(true)
+                    .and_then(|mut selection| selection.read())
+                    .map_err(|e| format!("unable to read the primary selection: {}", e)),
+                None => match SystemClipboard::open() {
+                    Ok(mut clipboard) => clipboard
+                        .read()
+                        .map_err(|e| format!("unable to read the clipboard: {}", e)),
+                    // e.g. over ssh, where piping in still works
+                    Err(e) => Err(format!(
+                        "unable to open clipboard: {}, `slate copy --stdin` takes piped input instead",
+                        e
+                    )),
+                },
             };
+            match read {
+                Err(e) => return failure(e),
+                Ok(None) => return failure("nothing to copy, it is empty".to_string()),
+                Ok(Some((data, mime))) => {
+                    // so the watcher does not store it a second time
+                    watch.lock().expect("failed to acquire lock").last_hash =
+                        Some(hash_entry(&data));
 
-            let (x, y) = oneshot::channel();
-            let msg = ControlMessage {
-                cmd: ControlCommand::Transmit {
-                    data: data.unwrap(),
-                    ttl: None,
-                    clock: None
-                },
+                    let (x, y) = oneshot::channel();
+                    let msg = ControlMessage {
+                        cmd: ControlCommand::Transmit {
+                            source: Source::Local,
+                            data,
+                            ttl: None,
+                            origin: None,
+                            mime: Some(mime),
+                            key: None,
+                            expires_at: ttl_secs.map(|secs| unix_now().saturating_add(secs)),
+                        },
+                        sender: x,
+                    };
+                    if let Err(e) = cp_tx.send(msg).await {
+                        return failure(format!("unable to send msg to control plane {}", e));
+                    }
+                    match y.await {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => return failure(format!("copying got error {}", e)),
+                        Err(_) => return failure("the control plane did not answer".to_string()),
+                    }
+                    match ttl_secs {
+                        Some(secs) => format!(
+                            "successfully copied to db, deleted everywhere in {}",
+                            format_uptime(Duration::from_secs(secs))
+                        ),
+                        None => "successfully copied to db".to_string(),
+                    }
+                }
+            }
+        }
+        // the current entry without an offset
+        Request::Paste { offset, primary } => match SystemClipboard::open_selection(primary) {
+            Err(e) => return failure(format!("unable to open clipboard: {}", e)),
+            Ok(clipboard) => {
+                let msg = DBMessage {
+                    cmd: DBCommand::Paste { offset, clipboard },
+                    sender: x,
+                };
+
+                if let Err(e) = tx.send(msg).await {
+                    return failure(format!("unable to send message to db {}", e));
+                } else {
+                    let response = y.await.expect("failed to read response");
+                    match response {
+                        Ok(Response::Pasted { preview, entry }) => {
+                            // so the watcher does not store it again as a new copy
+                            watch.lock().expect("failed to acquire lock").last_hash =
+                                Some(hash_entry(&entry));
+                            data = Some(json!({ "pasted": preview }));
+                            format!("pasted: {}\n", preview)
+                        }
+                        Err(e) => return failure(format!("error pasting to clipboard: {}", e)),
+                        _ => return failure("SHOULD NEVER PRINT?!".to_string()),
+                    }
+                }
+            }
+        },
+        // text as it is and images as png, for the cli to print
+        Request::PasteRaw { offset } => {
+            let msg = DBMessage {
+                cmd: DBCommand::Entry { offset },
                 sender: x,
             };
-            // doesnt matter if it fails to go through, we have anti entropy in place
-            let _ = cp_tx.send(msg).await;
-            let _resp = y.await;
-            format!("successfully copied to db")
+            if let Err(e) = tx.send(msg).await {
+                return failure(format!("unable to send message to db {}", e));
+            } else {
+                match y.await.expect("failed to read response") {
+                    Ok(Response::Entry { entry }) => match entry {
+                        ClipboardEntry::Text(text)
+                        | ClipboardEntry::Html { alt_text: text, .. } => {
+                            data = Some(json!({ "text": text }));
+                            text
+                        }
+                        ClipboardEntry::Image(image) => match image.to_png() {
+                            Some(png) => {
+                                data = Some(json!({
+                                    "width": image.width,
+                                    "height": image.height,
+                                    "png": STANDARD.encode(png),
+                                }));
+                                String::new()
+                            }
+                            None => {
+                                return failure("unable to encode the image as png".to_string())
+                            }
+                        },
+                    },
+                    Err(e) => return failure(format!("error reading entry: {}", e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
+                }
+            }
         }
-        cmd if cmd.starts_with("paste ") => {
-            let cmd = command.strip_prefix("paste ").unwrap();
-            let offset = cmd.parse::<usize>().unwrap();
-            let clipboard = arboard::Clipboard::new().expect("unable to open clipboard");
+        // gone here right away, peers drop it on gossip or their next sync
+        Request::Delete { offset } => {
+            let offset = offset.unwrap_or(0);
             let msg = DBMessage {
-                cmd: DBCommand::Paste {
-                    offset,
-                    clipboard: ClipboardWrapper { inner: clipboard },
-                },
+                cmd: DBCommand::KeyAt { offset },
                 sender: x,
             };
-
             if let Err(e) = tx.send(msg).await {
-                format!("unable to send message to db {}", e)
+                return failure(format!("unable to send message to db {}", e));
             } else {
-                let response = y.await.expect("failed to read response");
-                match response {
-                    Ok(_) => {
-                        format!("successfully pasted to clipboard")
-                    }
-                    Err(e) => {
-                        format!("error pasting to clipboard: {}", e)
+                match y.await.expect("failed to read response") {
+                    Ok(Response::Key { key }) => {
+                        let (x, y) = oneshot::channel();
+                        let msg = ControlMessage {
+                            cmd: ControlCommand::Delete {
+                                key,
+                                ttl: None,
+                                source: Source::Local,
+                                origin: None,
+                            },
+                            sender: x,
+                        };
+                        let _ = cp_tx.send(msg).await;
+                        match y.await {
+                            Ok(Ok(_)) => format!("deleted entry {}", offset),
+                            Ok(Err(e)) => return failure(format!("error deleting entry: {}", e)),
+                            Err(e) => return failure(format!("error deleting entry: {}", e)),
+                        }
                     }
+                    Err(e) => return failure(format!("error deleting entry: {}", e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
                 }
             }
         }
-        "history" => {
+        Request::History {
+            limit,
+            offset,
+            thumbnails,
+        } => {
             if tx
                 .send(DBMessage {
-                    cmd: DBCommand::History,
+                    cmd: DBCommand::History {
+                        limit,
+                        offset,
+                        thumbnails,
+                    },
                     sender: x,
                 })
                 .await
                 .is_err()
             {
-                format!("failed to send message to db")
+                return failure("failed to send message to db".to_string());
+            } else {
+                match y.await.expect("failed to read response") {
+                    Ok(Response::History { entries, more }) => {
+                        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+                        let origins: Vec<_> = entries.iter().map(|e| e.origin.as_deref()).collect();
+                        let mut json =
+                            json!({ "history": names, "origins": origins, "more": more });
+                        if thumbnails {
+                            // base64 pngs, null for entries that are not images
+                            json["thumbnails"] = entries
+                                .iter()
+                                .map(|e| e.thumbnail.as_ref().map(|png| STANDARD.encode(png)))
+                                .collect();
+                        }
+                        data = Some(json);
+                        let mut text = format!("history {}\n", names.join(" "));
+                        if more {
+                            text += &format!("more with --offset {}\n", offset + names.len());
+                        }
+                        text
+                    }
+                    Err(e) => return failure(format!("error getting history {}", e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
+                }
+            }
+        }
+        Request::Status => {
+            let (x, y) = oneshot::channel();
+            let msg = ControlMessage {
+                cmd: ControlCommand::Status,
+                sender: x,
+            };
+            if let Err(e) = cp_tx.send(msg).await {
+                return failure(format!("unable to send msg to control plane {}", e));
+            } else {
+                match y.await.expect("failed to read response") {
+                    Ok(crate::control_plane::Response::Status {
+                        started_at,
+                        neighbors,
+                        port,
+                    }) => {
+                        let uptime = started_at.elapsed().unwrap_or_default();
+                        let since = started_at
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs();
+                        data = Some(json!({
+                            "pid": std::process::id(),
+                            "started_at": since,
+                            "uptime_secs": uptime.as_secs(),
+                            "neighbors": neighbors,
+                            "port": port,
+                        }));
+                        format!(
+                            "running pid {}, up {} (since unix {}), {} neighbors, http port {}\n",
+                            std::process::id(),
+                            format_uptime(uptime),
+                            since,
+                            neighbors,
+                            port
+                        )
+                    }
+                    Err(e) => return failure(format!("error getting status {}\n", e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
+                }
+            }
+        }
+        Request::Peers => {
+            let (x, y) = oneshot::channel();
+            let msg = ControlMessage {
+                cmd: ControlCommand::GetNeighbors,
+                sender: x,
+            };
+            if let Err(e) = cp_tx.send(msg).await {
+                return failure(format!("unable to send msg to control plane {}", e));
+            } else {
+                match y.await.expect("failed to read response") {
+                    Ok(crate::control_plane::Response::Neighbors { mut info, health }) => {
+                        info.sort_by(|a, b| a.HostName.cmp(&b.HostName));
+                        data = Some(json!({ "peers": peers_json(&info, &health) }));
+                        if info.is_empty() {
+                            "NO PEERS\n".to_string()
+                        } else {
+                            format_peers(info, &health)
+                        }
+                    }
+                    Err(e) => return failure(format!("error getting peers {}\n", e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
+                }
+            }
+        }
+        Request::Clock => {
+            let (x, y) = oneshot::channel();
+            let msg = ControlMessage {
+                cmd: ControlCommand::GetClock,
+                sender: x,
+            };
+            if let Err(e) = cp_tx.send(msg).await {
+                return failure(format!("unable to send msg to control plane {}", e));
+            } else {
+                match y.await.expect("failed to read response") {
+                    Ok(crate::control_plane::Response::Clock { clock, host_name }) => {
+                        data = Some(json!({ "clock": clock, "self": host_name }));
+                        format_clock(clock, &host_name)
+                    }
+                    Err(e) => return failure(format!("error getting clock {}\n", e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
+                }
+            }
+        }
+        Request::Sync { dry_run } => {
+            let (x, y) = oneshot::channel();
+            let msg = ControlMessage {
+                cmd: ControlCommand::AntiEntropy { dry_run },
+                sender: x,
+            };
+            if let Err(e) = cp_tx.send(msg).await {
+                return failure(format!("unable to send msg to control plane {}", e));
             } else {
                 match y.await.expect("failed to read response") {
-                    Ok(Response::History { names }) => {
-                        format!("history {}", names.join(" "))
+                    Ok(crate::control_plane::Response::Synced { pulled }) if dry_run => {
+                        let hosts: Vec<_> = pulled
+                            .iter()
+                            .map(|(host, n)| json!({ "host": host, "entries": n }))
+                            .collect();
+                        data = Some(json!({ "ahead": hosts }));
+                        if pulled.is_empty() {
+                            "up to date with every peer\n".to_string()
+                        } else {
+                            let peers: Vec<String> = pulled
+                                .iter()
+                                .map(|(host, n)| format!("{} (about {})", host, n))
+                                .collect();
+                            format!("would pull from {}\n", peers.join(", "))
+                        }
                     }
-                    Err(e) => format!("error getting history {}", e),
-                    _ => {
-                        format!("SHOULD NEVER PRINT?!\n")
+                    Ok(crate::control_plane::Response::Synced { pulled }) => {
+                        let hosts: Vec<_> = pulled
+                            .iter()
+                            .map(|(host, n)| json!({ "host": host, "entries": n }))
+                            .collect();
+                        data = Some(json!({ "pulled": hosts }));
+                        if pulled.is_empty() {
+                            "no new entries, missing files are pulled in the background\n"
+                                .to_string()
+                        } else {
+                            let total: usize = pulled.iter().map(|(_, n)| n).sum();
+                            let peers: Vec<String> = pulled
+                                .iter()
+                                .map(|(host, n)| format!("{} ({})", host, n))
+                                .collect();
+                            format!("pulled {} entries from {}\n", total, peers.join(", "))
+                        }
                     }
+                    Err(e) => return failure(format!("error syncing {}\n", e)),
+                    _ => return failure("SHOULD NEVER PRINT?!".to_string()),
                 }
             }
         }
-        _ => format!("hey {}\n", command),
+        Request::Watch { enabled } => {
+            watch.lock().expect("failed to acquire lock").enabled = enabled;
+            if enabled {
+                "clipboard watcher enabled\n".to_string()
+            } else {
+                "clipboard watcher disabled\n".to_string()
+            }
+        }
     };
 
-    if let Err(e) = reader.get_mut().write_all(response.as_bytes()).await {
-        eprintln!("failed to send response: {}", e);
+    match data {
+        Some(data) => Reply::Output { text, data },
+        None => Reply::Message {
+            text: text.trim().to_string(),
+        },
     }
 }
 
-pub fn stop_daemon() -> Result<(), ()> {
-    if let Ok(pid) = fs::read_to_string(PID_FILE) {
-        let pid: i32 = pid.trim().parse().unwrap();
-        unsafe { libc::kill(pid, libc::SIGTERM) };
-        fs::remove_file(PID_FILE).unwrap();
-        fs::remove_file(SOCKET_PATH).unwrap();
-        Ok(())
+// what went wrong, so the cli can exit with an error
+fn failure(text: String) -> Reply {
+    Reply::Error {
+        text: text.trim().to_string(),
+    }
+}
+
+// in a frame, or as a line of json to a cli that sent one
+async fn write_reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: &Reply, framed: bool) {
+    let mut bytes = serde_json::to_vec(reply).expect("failed to serialize reply");
+    if framed {
+        bytes = protocol::encode(protocol::REPLY, &bytes);
     } else {
-        Err(())
+        bytes.push(b'\n');
+    }
+    if let Err(e) = stream.write_all(&bytes).await {
+        error!("failed to send response: {}", e);
     }
 }
 
-fn fallback_get_clipboard_hyprland() -> Result<String, ()> {
-    println!("trying to read clipboard via wl-paste");
-    use std::process::Command;
-    let output = Command::new("wl-paste").arg("--no-newline").output().ok();
+// false if it was not running
+pub fn stop_daemon(config: &Config) -> Result<bool, String> {
+    let path = pid_file(config);
+    let Ok(pid) = fs::read_to_string(&path) else {
+        return Ok(false);
+    };
+    let pid: u32 = pid.trim().parse().map_err(|_| {
+        format!(
+            "invalid pid file {}, remove it if slate is not running",
+            path.display()
+        )
+    })?;
+    platform::terminate(pid);
+    fs::remove_file(&path)
+        .map_err(|e| format!("failed to remove pid file {}: {}", path.display(), e))?;
+    ipc::cleanup(&config.socket_path);
+    Ok(true)
+}
 
-    if let Some(output) = output {
-        if output.status.success() {
-            println!("read from wl-paste");
-            Ok(String::from_utf8(output.stdout).expect("failed to convert from utf8"))
-        } else {
-            println!("wl-paste failed");
-            Err(())
+// prints the last lines of the daemon's log, and with follow keeps printing
+// what it writes until interrupted, like tail -f
+pub fn show_log(config: &Config, lines: usize, follow: bool) -> Result<(), String> {
+    let path = log_file(config);
+    let file =
+        fs::File::open(&path).map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    let mut last = VecDeque::with_capacity(lines);
+    let mut read = 0;
+    for line in std::io::BufReader::new(file).split(b'\n') {
+        let line = line.map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        read += line.len() as u64 + 1;
+        if last.len() == lines {
+            last.pop_front();
         }
+        if lines > 0 {
+            last.push_back(line);
+        }
+    }
+    let mut stdout = std::io::stdout().lock();
+    for line in last {
+        stdout
+            .write_all(&line)
+            .and_then(|_| stdout.write_all(b"\n"))
+            .map_err(|e| e.to_string())?;
+    }
+    stdout.flush().map_err(|e| e.to_string())?;
+    if !follow {
+        return Ok(());
+    }
+
+    // polled rather than watched, the log is only written now and then
+    loop {
+        std::thread::sleep(LOG_POLL_INTERVAL);
+        let Ok(mut file) = fs::File::open(&path) else {
+            // gone while the daemon restarts
+            continue;
+        };
+        let len = file.metadata().map(|m| m.len()).unwrap_or_default();
+        // a new log, from a daemon started since
+        if len < read {
+            read = 0;
+        }
+        if len == read {
+            continue;
+        }
+        file.seek(SeekFrom::Start(read))
+            .map_err(|e| e.to_string())?;
+        read += std::io::copy(&mut file, &mut stdout).map_err(|e| e.to_string())?;
+        stdout.flush().map_err(|e| e.to_string())?;
+    }
+}
+
+fn hash_entry(entry: &ClipboardEntry) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match entry {
+        ClipboardEntry::Text(t) => t.hash(&mut hasher),
+        ClipboardEntry::Image(i) => i.hash(&mut hasher),
+        ClipboardEntry::Html { html, alt_text } => (html, alt_text).hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+// polls the os clipboard and stores anything new while the watcher is enabled
+async fn watch_clipboard(
+    watch: SharedWatch,
+    cp_tx: mpsc::Sender<ControlMessage>,
+    interval: Duration,
+) {
+    // content that changed on the last poll, stored once it holds still for
+    // another one, so a burst of copies only stores where it settled
+    let mut pending = None;
+    loop {
+        sleep(interval).await;
+        if !watch.lock().expect("failed to acquire lock").enabled {
+            pending = None;
+            continue;
+        }
+
+        let read = SystemClipboard::open().and_then(|mut clipboard| clipboard.read());
+        let (data, mime) = match read {
+            Ok(Some(read)) => read,
+            Ok(None) => continue,
+            Err(e) => {
+                debug!("watcher could not read the clipboard: {}", e);
+                continue;
+            }
+        };
+        let hash = hash_entry(&data);
+        {
+            let mut state = watch.lock().expect("failed to acquire lock");
+            if state.last_hash == Some(hash) {
+                pending = None;
+                continue;
+            }
+            if pending != Some(hash) {
+                pending = Some(hash);
+                continue;
+            }
+            pending = None;
+            state.last_hash = Some(hash);
+        }
+
+        debug!("clipboard changed, storing it");
+        let (x, y) = oneshot::channel();
+        let msg = ControlMessage {
+            cmd: ControlCommand::Transmit {
+                source: Source::Local,
+                data,
+                ttl: None,
+                origin: None,
+                mime: Some(mime),
+                key: None,
+                expires_at: None,
+            },
+            sender: x,
+        };
+        let _ = cp_tx.send(msg).await;
+        let _ = y.await;
+    }
+}
+
+// wakes the db up to delete expired entries, even while nothing else happens
+async fn purge_expired(tx: mpsc::Sender<DBMessage>) {
+    loop {
+        sleep(PURGE_INTERVAL).await;
+        let (x, y) = oneshot::channel();
+        let msg = DBMessage {
+            cmd: DBCommand::PurgeExpired,
+            sender: x,
+        };
+        if tx.send(msg).await.is_err() {
+            return;
+        }
+        let _ = y.await;
+    }
+}
+
+fn peers_json(peers: &[PeerInfo], health: &HashMap<String, PeerHealth>) -> Vec<serde_json::Value> {
+    peers
+        .iter()
+        .map(|p| {
+            let health = health.get(&p.HostName).cloned().unwrap_or_default();
+            json!({
+                "host": p.HostName,
+                "ips": p.TailscaleIPs,
+                "online": p.Online,
+                "failures": health.failures,
+                "retry_in_secs": health.backoff_left().map(|left| left.as_secs()),
+            })
+        })
+        .collect()
+}
+
+// how requests to a peer went lately, in words
+fn format_health(health: Option<&PeerHealth>) -> String {
+    let Some(health) = health.filter(|h| h.failures > 0) else {
+        return "ok".to_string();
+    };
+    let failures = match health.failures {
+        1 => "1 failure".to_string(),
+        n => format!("{} failures", n),
+    };
+    match health.backoff_left() {
+        Some(left) => format!("{}, retry in {}", failures, format_uptime(left)),
+        None => failures,
+    }
+}
+
+fn files_json(files: &[FileInfo]) -> Vec<serde_json::Value> {
+    files
+        .iter()
+        .map(|f| {
+            json!({
+                "name": f.name,
+                "size_bytes": f.size_bytes,
+                "uploaded_at": f.uploaded_at.map(|u| u.to_string()),
+                "source_host": f.source_host,
+                "hash": f.hash,
+            })
+        })
+        .collect()
+}
+
+fn format_peers(peers: Vec<PeerInfo>, health: &HashMap<String, PeerHealth>) -> String {
+    let rows: Vec<(String, String, &str, String)> = peers
+        .iter()
+        .map(|p| {
+            let ips = if p.TailscaleIPs.is_empty() {
+                "-".to_string()
+            } else {
+                p.TailscaleIPs.join(", ")
+            };
+            let status = if p.Online { "online" } else { "OFFLINE" };
+            let health = format_health(health.get(&p.HostName));
+            (p.HostName.clone(), ips, status, health)
+        })
+        .collect();
+
+    let host_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0).max(4);
+    let ip_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0).max(3);
+
+    let mut table = format!(
+        "{:<host_width$}  {:<ip_width$}  {:<7}  HEALTH\n",
+        "HOST", "IPS", "STATUS"
+    );
+    for (host, ips, status, health) in rows {
+        table += &format!(
+            "{:<host_width$}  {:<ip_width$}  {:<7}  {}\n",
+            host, ips, status, health
+        );
+    }
+    table
+}
+
+// sorted by host, marking our own row
+fn format_clock(clock: Clock, host_name: &str) -> String {
+    let mut rows: Vec<(String, u64)> = clock.into_iter().collect();
+    rows.sort();
+    let host_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0).max(4);
+
+    let mut table = format!("{:<host_width$}  COUNTER\n", "HOST");
+    for (host, counter) in rows {
+        let note = if host == host_name {
+            "  (this device)"
+        } else {
+            ""
+        };
+        table += &format!("{:<host_width$}  {:>7}{}\n", host, counter, note);
+    }
+    table
+}
+
+fn format_files(files: Vec<FileInfo>) -> String {
+    let rows: Vec<(String, String, String, String)> = files
+        .into_iter()
+        .map(|f| {
+            let size = f.size_bytes.map(format_bytes).unwrap_or("-".to_string());
+            let uploaded = f
+                .uploaded_at
+                .and_then(|u| u.datetime().elapsed().ok())
+                .map(|age| format!("{} ago", format_uptime(age)))
+                .unwrap_or("-".to_string());
+            let host = f.source_host.unwrap_or("-".to_string());
+            (f.name, size, uploaded, host)
+        })
+        .collect();
+
+    let name_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0).max(4);
+    let size_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0).max(4);
+    let uploaded_width = rows.iter().map(|r| r.2.len()).max().unwrap_or(0).max(8);
+
+    let mut table = format!(
+        "{:<name_width$}  {:<size_width$}  {:<uploaded_width$}  HOST\n",
+        "NAME", "SIZE", "UPLOADED"
+    );
+    for (name, size, uploaded, host) in rows {
+        table += &format!(
+            "{:<name_width$}  {:<size_width$}  {:<uploaded_width$}  {}\n",
+            name, size, uploaded, host
+        );
+    }
+    table
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, mins)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
     } else {
-        println!("wl-paste couldnt start?");
-        Err(())
+        format!("{}s", secs)
     }
 }
@@ -1,27 +1,135 @@
 use arboard::ImageData;
-use rusqlite::{params, Connection};
-use serde::{Deserialize, Serialize};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::{imageops::FilterType, ImageFormat, RgbaImage};
+use rusqlite::types::{Type, Value};
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::{fs, io::Read};
-use tokio::sync::mpsc::Receiver;
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    fs,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+};
+use tokio::sync::mpsc::{self, Receiver};
 use tokio::sync::oneshot::Sender;
+use tracing::{debug, debug_span, error, info, trace, warn};
 use ulid::Ulid;
 use zstd::stream::encode_all;
 
-const DATABASE_PATH: &str = "/tmp/slate_daemon.sqlite";
+use crate::clipboard::SystemClipboard;
+use crate::config::Config;
+use crate::control_plane::{merge_clock, PeerInfo};
+use crate::crypto::{self, Cipher};
+
 pub type Clock = HashMap<String, u64>;
 
 pub struct Database {
     connection: Connection,
+    // how many entries the history lists
+    max_history: usize,
+    // files larger than this are refused on upload
+    max_upload_bytes: u64,
+    // zstd level of uploaded files
+    compression_level: i32,
+    // encrypts clipboard entries and files at rest, with SLATE_PASSPHRASE set
+    storage: Option<Cipher>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// which encrypted column a value is in, so each gets its own nonce
+const CHECK_COLUMN: u8 = 0;
+const TEXT_COLUMN: u8 = 1;
+const HTML_COLUMN: u8 = 2;
+const IMAGE_COLUMN: u8 = 3;
+const FILE_COLUMN: u8 = 4;
+const THUMBNAIL_COLUMN: u8 = 5;
+// longest side of the previews stored with image entries
+const THUMBNAIL_SIZE: u32 = 128;
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+// how long a deleted entry is remembered. a peer that was offline for longer
+// than this can bring the entry back
+const TOMBSTONE_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+// how long a connection waits for the other one to finish writing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// entries whose time is up stay until the next purge, reads leave them out
+const NOT_EXPIRED: &str = "(expires_at IS NULL OR expires_at > unixepoch())";
+// columns added to the tables since the first release, oldest first. older
+// dbs get them on startup, and PRAGMA user_version counts how many of these
+// a db has been through. only ever append to this list
+const MIGRATIONS: &[(&str, &str, &str)] = &[
+    ("files", "size_bytes", "INTEGER"),
+    ("files", "uploaded_at", "TEXT"),
+    ("files", "source_host", "TEXT"),
+    ("files", "hash", "TEXT"),
+    ("files", "nonce", "BLOB"),
+    ("clipboard", "html_data", "TEXT"),
+    ("clipboard", "origin", "TEXT"),
+    ("clipboard", "counter", "INTEGER"),
+    ("clipboard", "mime", "TEXT"),
+    ("clipboard", "thumbnail", "BLOB"),
+    ("clipboard", "nonce", "BLOB"),
+    ("clipboard", "expires_at", "INTEGER"),
+];
+// sealed into the meta table to tell a wrong passphrase from a right one
+const CHECK_VALUE: &[u8] = b"slate";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
 pub struct SerializableImage {
-    width: usize,
-    height: usize,
-    bytes: Vec<u8>, // owned!
+    pub width: usize,
+    pub height: usize,
+    #[serde(with = "base64_bytes")]
+    pub bytes: Vec<u8>, // owned!
+}
+
+// rgba bytes as a base64 string, rather than serde's array of numbers which
+// is several times larger. peers on older versions still send the array
+mod base64_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Bytes {
+            Base64(String),
+            Array(Vec<u8>),
+        }
+        match Bytes::deserialize(deserializer)? {
+            Bytes::Base64(s) => STANDARD.decode(s).map_err(serde::de::Error::custom),
+            Bytes::Array(bytes) => Ok(bytes),
+        }
+    }
+}
+
+impl SerializableImage {
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0 || self.bytes.is_empty()
+    }
+
+    pub fn to_png(&self) -> Option<Vec<u8>> {
+        let rgba = rgba_image(self)?;
+        encode_png(&image::DynamicImage::ImageRgba8(rgba))
+    }
+
+    // a png or jpeg file, as piped in or offered by other apps
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let image = image::load_from_memory(bytes)
+            .map_err(|e| e.to_string())?
+            .to_rgba8();
+        Ok(SerializableImage {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            bytes: image.into_raw(),
+        })
+    }
 }
 
 impl<'a> From<ImageData<'a>> for SerializableImage {
@@ -34,50 +142,328 @@ impl<'a> From<ImageData<'a>> for SerializableImage {
     }
 }
 
-impl<'a> Into<ImageData<'a>> for SerializableImage {
-    fn into(self) -> ImageData<'a> {
+impl<'a> From<SerializableImage> for ImageData<'a> {
+    fn from(img: SerializableImage) -> Self {
         ImageData {
-            width: self.width,
-            height: self.height,
-            bytes: Cow::Owned(self.bytes),
+            width: img.width,
+            height: img.height,
+            bytes: Cow::Owned(img.bytes),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum ClipboardEntry {
     Image(SerializableImage),
     Text(String),
+    // alt_text is what consumers without html support get
+    Html { html: String, alt_text: String },
+}
+
+// only sizes, so commands and records can be logged without the passwords
+// and such that people copy
+impl Debug for ClipboardEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClipboardEntry::Image(i) => write!(f, "Image({}x{})", i.width, i.height),
+            ClipboardEntry::Text(t) => write!(f, "Text({} bytes)", t.len()),
+            ClipboardEntry::Html { html, alt_text } => write!(
+                f,
+                "Html({} bytes, {} bytes of text)",
+                html.len(),
+                alt_text.len()
+            ),
+        }
+    }
+}
+
+// how long a preview of pasted text may get
+const PREVIEW_CHARS: usize = 80;
+
+impl ClipboardEntry {
+    // a single line summary of the entry, images by their size only
+    pub fn preview(&self) -> String {
+        let text = match self {
+            ClipboardEntry::Image(i) => return format!("image {}x{}", i.width, i.height),
+            ClipboardEntry::Text(t) => t,
+            ClipboardEntry::Html { alt_text, .. } => alt_text,
+        };
+        let line: String = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        match line.char_indices().nth(PREVIEW_CHARS) {
+            Some((end, _)) => format!("{}...", &line[..end]),
+            None => line,
+        }
+    }
+}
+
+// a line of `slate history`. image entries carry their png thumbnail when
+// it was asked for
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub name: String,
+    pub thumbnail: Option<Vec<u8>>,
+    // host the entry was copied on, None for entries from before it was kept
+    pub origin: Option<String>,
+}
+
+// the host that created an entry, and its clock counter at the time
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Origin {
+    pub host: String,
+    pub counter: u64,
+}
+
+// what `slate files` shows about a stored file. rows from before these
+// were recorded have none of them
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    pub name: String,
+    pub size_bytes: Option<u64>,
+    pub uploaded_at: Option<Ulid>,
+    pub source_host: Option<String>,
+    pub hash: Option<String>,
+}
+
+// what peers list of our files, so they can pull the ones they lack
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SharedFile {
+    pub name: String,
+    pub hash: String,
+    pub size_bytes: u64,
+    pub uploaded_at: Option<String>,
+    pub source_host: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClipboardRecord {
+    pub key: String,
+    pub entry: ClipboardEntry,
+    pub origin: Option<Origin>,
+    // content type the entry was offered as when it was copied
+    #[serde(default)]
+    pub mime: Option<String>,
+    // unix seconds after which every node deletes the entry
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    // a tombstone, the entry with this key was deleted. its origin is the
+    // host that deleted it, and the entry is left empty
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+impl ClipboardRecord {
+    fn tombstone(key: String, origin: Origin) -> Self {
+        ClipboardRecord {
+            key,
+            entry: ClipboardEntry::Text(String::new()),
+            origin: Some(origin),
+            mime: None,
+            expires_at: None,
+            deleted: true,
+        }
+    }
+}
+
+// what `slate export` writes, read back by `slate import`. it also starts
+// with the schema version, host and time it was exported at, for people
+#[derive(Deserialize)]
+struct ExportFile {
+    // schema version, missing from exports older than it
+    #[serde(default)]
+    version: usize,
+    clock: Clock,
+    entries: Vec<ClipboardRecord>,
+    // only in exports written to a file
+    #[serde(default)]
+    files: Vec<ExportedFile>,
+}
+
+// a file in an export, its content compressed as it is stored
+#[derive(Serialize, Deserialize)]
+struct ExportedFile {
+    #[serde(flatten)]
+    file: SharedFile,
+    // base64
+    content: String,
 }
 
 impl Database {
-    pub fn new() -> Result<Self, rusqlite::Error> {
-        let connection = Connection::open(DATABASE_PATH)?;
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let connection = connect(&config.db_path)?;
         //let connection = Connection::open_in_memory()?;
+        // lets the read connection go on while this one writes. it sticks to
+        // the db file, so the read connection gets it too
+        let journal_mode: String = connection
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            warn!(
+                "database is in {} mode, reads wait for writes to finish",
+                journal_mode
+            );
+        }
         let sql = "
             CREATE TABLE IF NOT EXISTS files (
                 key INTEGER NOT NULL PRIMARY KEY,
                 file_name TEXT UNIQUE NOT NULL,
-                content BLOB NOT NULL
+                content BLOB NOT NULL,
+                -- size before compression
+                size_bytes INTEGER,
+                -- ulid taken when the file was uploaded
+                uploaded_at TEXT,
+                -- host the file was uploaded on
+                source_host TEXT,
+                -- sha256 of the uncompressed content, as hex
+                hash TEXT,
+                -- set when the content is encrypted at rest
+                nonce BLOB
             );
             CREATE TABLE IF NOT EXISTS clipboard (
                 -- using ULID for key, can sort by time, while unique across nodes
                 key TEXT NOT NULL PRIMARY KEY,
+                -- for html entries this holds the plain text alternative
                 text_data TEXT,
+                html_data TEXT,
                 width INTEGER,
                 height INTEGER,
-                image_content BLOB 
+                image_content BLOB,
+                -- host that created the entry, and its counter at the time
+                origin TEXT,
+                counter INTEGER,
+                -- primary content type offered by the os clipboard
+                mime TEXT,
+                -- small png preview of image entries
+                thumbnail BLOB,
+                -- set when text, html, image and thumbnail are encrypted at rest
+                nonce BLOB,
+                -- unix seconds after which the entry is deleted, for secrets
+                expires_at INTEGER
             );
             CREATE TABLE IF NOT EXISTS clock (
                 key TEXT NOT NULL PRIMARY KEY,
                 self BOOLEAN NOT NULL,
                 time INTEGER NOT NULL
+            );
+            -- entries deleted on any node, so copies that peers still have
+            -- are dropped instead of stored again
+            CREATE TABLE IF NOT EXISTS tombstones (
+                -- ulid of the deleted entry
+                key TEXT NOT NULL PRIMARY KEY,
+                -- host that deleted it, and its counter at the time
+                origin TEXT NOT NULL,
+                counter INTEGER NOT NULL,
+                -- unix seconds, tombstones are forgotten after a while
+                deleted_at INTEGER NOT NULL
+            );
+            -- peers that were online at the last discovery, to start from
+            CREATE TABLE IF NOT EXISTS neighbors (
+                host_name TEXT NOT NULL PRIMARY KEY,
+                ip TEXT NOT NULL,
+                -- unix seconds
+                last_seen INTEGER NOT NULL
+            );
+            -- the salt of the at rest key, and a value sealed with it
+            CREATE TABLE IF NOT EXISTS meta (
+                key TEXT NOT NULL PRIMARY KEY,
+                value BLOB NOT NULL
             )
         ";
 
-        connection.execute_batch(sql)?;
+        connection.execute_batch(sql).map_err(|e| e.to_string())?;
+        migrate(&connection)?;
+        // after migrating, older dbs only have expires_at from then on
+        connection
+            .execute(
+                "CREATE INDEX IF NOT EXISTS clipboard_expires_at ON clipboard (expires_at)",
+                [],
+            )
+            .map_err(|e| e.to_string())?;
+        let storage = open_storage(&connection, crypto::load_passphrase())?;
+
+        Ok(Database {
+            connection,
+            max_history: config.max_history,
+            max_upload_bytes: config.max_upload_bytes,
+            compression_level: config.compression_level,
+            storage,
+        })
+    }
+
+    // a second connection to the same db, for the commands that only read.
+    // only open it after new, which sets the db up
+    pub fn reader(&self, config: &Config) -> Result<Self, String> {
+        let connection = connect(&config.db_path)?;
+        Ok(Database {
+            connection,
+            max_history: self.max_history,
+            max_upload_bytes: self.max_upload_bytes,
+            compression_level: self.compression_level,
+            storage: self.storage.clone(),
+        })
+    }
+
+    // a nonce for a new row, if rows are encrypted at all
+    fn row_nonce(&self) -> Option<Vec<u8>> {
+        self.storage.as_ref().map(|_| crypto::new_nonce())
+    }
+
+    fn seal_bytes(&self, nonce: Option<&[u8]>, column: u8, data: Vec<u8>) -> Vec<u8> {
+        match (&self.storage, nonce) {
+            (Some(cipher), Some(nonce)) => crypto::encrypt_column(cipher, nonce, column, &data),
+            _ => data,
+        }
+    }
+
+    // plain text is kept as TEXT, encrypted text is a BLOB
+    fn seal_text(&self, nonce: Option<&[u8]>, column: u8, text: String) -> Value {
+        match (&self.storage, nonce) {
+            (Some(cipher), Some(nonce)) => Value::Blob(crypto::encrypt_column(
+                cipher,
+                nonce,
+                column,
+                text.as_bytes(),
+            )),
+            _ => Value::Text(text),
+        }
+    }
 
-        Ok(Database { connection })
+    fn open_bytes(
+        &self,
+        row: &rusqlite::Row,
+        idx: usize,
+        nonce: Option<&[u8]>,
+        column: u8,
+    ) -> Result<Option<Vec<u8>>, rusqlite::Error> {
+        let data: Option<Vec<u8>> = row.get(idx)?;
+        match (data, nonce) {
+            (Some(data), Some(nonce)) => {
+                let cipher = self.storage.as_ref().ok_or_else(|| {
+                    conversion_error(idx, "row is encrypted but no passphrase is set")
+                })?;
+                crypto::decrypt_column(cipher, nonce, column, &data)
+                    .map(Some)
+                    .map_err(|e| conversion_error(idx, e))
+            }
+            (data, _) => Ok(data),
+        }
+    }
+
+    fn open_text(
+        &self,
+        row: &rusqlite::Row,
+        idx: usize,
+        nonce: Option<&[u8]>,
+        column: u8,
+    ) -> Result<Option<String>, rusqlite::Error> {
+        if nonce.is_none() {
+            return row.get(idx);
+        }
+        match self.open_bytes(row, idx, nonce, column)? {
+            Some(bytes) => String::from_utf8(bytes)
+                .map(Some)
+                .map_err(|e| conversion_error(idx, e.to_string())),
+            None => Ok(None),
+        }
     }
 
     fn sync_clock(&self, clock_map: &Clock) -> Result<(), rusqlite::Error> {
@@ -85,19 +471,20 @@ impl Database {
             return Ok(());
         }
 
-        // Create the parameterized query for non-self entries only
         let placeholders: Vec<_> = (0..clock_map.len())
             .map(|i| format!("(?{}, FALSE, ?{})", i * 2 + 1, i * 2 + 2))
             .collect();
 
+        // counters only ever go up. that includes our own: a peer that has
+        // seen a higher counter of ours than we have, say after our db was
+        // wiped, would take our next entries for ones it already has
         let sql = format!(
-            "INSERT INTO clock (key, self, time) VALUES {} 
-             ON CONFLICT(key) DO UPDATE SET time = excluded.time 
-             WHERE self = FALSE", // Only update non-self entries
+            "INSERT INTO clock (key, self, time) VALUES {}
+             ON CONFLICT(key) DO UPDATE SET time = MAX(time, excluded.time)",
             placeholders.join(",")
         );
 
-        // Convert HashMap entries to parameters (excluding self entries)
+        // Convert HashMap entries to parameters
         let params: Vec<_> = clock_map
             .iter()
             .flat_map(|(k, v)| vec![k as &dyn rusqlite::ToSql, v as &dyn rusqlite::ToSql])
@@ -121,10 +508,77 @@ impl Database {
         Ok(clock_map)
     }
 
-    fn inc_self_counter(&self) -> Result<(), rusqlite::Error> {
-        let sql = "UPDATE clock SET time = time + 1 WHERE self = TRUE";
-        self.connection.execute(sql, [])?;
-        Ok(())
+    // drops the clock rows of hosts that are no longer peers. the self row is
+    // never removed. if a pruned host rejoins the tailnet its counter starts
+    // again from zero on our side, so the next anti-entropy round re-pulls its
+    // recent entries (the inserts are keyed by ulid, so nothing is duplicated)
+    fn prune_clock(&self, keep: &[String]) -> Result<usize, rusqlite::Error> {
+        // an empty peer list most likely means discovery failed, not that
+        // every node left, so we refuse to wipe the clock
+        if keep.is_empty() {
+            return Ok(0);
+        }
+
+        let placeholders: Vec<_> = (0..keep.len()).map(|i| format!("?{}", i + 1)).collect();
+        let sql = format!(
+            "DELETE FROM clock WHERE self = FALSE AND key NOT IN ({})",
+            placeholders.join(",")
+        );
+
+        let params: Vec<_> = keep.iter().map(|k| k as &dyn rusqlite::ToSql).collect();
+        self.connection.execute(&sql, &params[..])
+    }
+
+    // replaces the saved peers with the given ones, each with a single address
+    fn save_neighbors(&self, peers: &[PeerInfo]) -> Result<(), rusqlite::Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute("DELETE FROM neighbors", [])?;
+        for peer in peers {
+            if let Some(ip) = peer.TailscaleIPs.first() {
+                transaction.execute(
+                    "INSERT INTO neighbors (host_name, ip, last_seen) VALUES (?1, ?2, ?3)",
+                    params![peer.HostName, ip, now],
+                )?;
+            }
+        }
+        transaction.commit()
+    }
+
+    fn load_neighbors(&self) -> Result<Vec<PeerInfo>, rusqlite::Error> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT host_name, ip FROM neighbors ORDER BY host_name")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(PeerInfo {
+                HostName: row.get(0)?,
+                TailscaleIPs: vec![row.get(1)?],
+                Online: true,
+            })
+        })?;
+        rows.collect()
+    }
+
+    fn inc_self_counter(&self) -> Result<Origin, rusqlite::Error> {
+        let sql = "UPDATE clock SET time = time + 1 WHERE self = TRUE RETURNING key, time";
+        self.connection.query_row(sql, [], |row| {
+            Ok(Origin {
+                host: row.get(0)?,
+                counter: row.get(1)?,
+            })
+        })
+    }
+
+    // our own host name, None until the control plane has set it
+    fn self_host(&self) -> Result<Option<String>, rusqlite::Error> {
+        self.connection
+            .query_row("SELECT key FROM clock WHERE self = TRUE", [], |row| {
+                row.get(0)
+            })
+            .optional()
     }
 
     fn upload_file(
@@ -133,31 +587,194 @@ impl Database {
         filepath: &str,
         timestamp: Ulid,
         local: bool,
-    ) -> Result<(), rusqlite::Error> {
-        if local {
-            self.inc_self_counter()?;
+    ) -> Result<(), String> {
+        check_file_name(filename)?;
+        debug!("opening file from {} with name {}", filepath, filename);
+        let mut file = fs::File::open(filepath).map_err(|e| format!("cannot open file: {}", e))?;
+        // check the size up front, before anything is read into memory
+        let size = file.metadata().map_err(|e| e.to_string())?.len();
+        if size > self.max_upload_bytes {
+            return Err(format!(
+                "file {} is {}, exceeds limit {}",
+                filename,
+                format_bytes(size),
+                format_bytes(self.max_upload_bytes)
+            ));
         }
-        println!("opening file from {} with name {}", filepath, filename);
-        let mut file = fs::File::open(filepath).expect("cannot open file");
+
         let mut file_data = Vec::new();
         file.read_to_end(&mut file_data)
-            .expect("failed to read file");
+            .map_err(|e| format!("failed to read file: {}", e))?;
 
-        let compressed_data = encode_all(&file_data[..], 3).unwrap();
+        let compressed_data =
+            encode_all(&file_data[..], self.compression_level).map_err(|e| e.to_string())?;
+        let hash = hex(&Sha256::digest(&file_data));
+        let nonce = self.row_nonce();
+        let compressed_data = self.seal_bytes(nonce.as_deref(), FILE_COLUMN, compressed_data);
+        // files are not on the clock, so this does not take a counter
+        let source_host = if local {
+            self.self_host().map_err(|e| e.to_string())?
+        } else {
+            None
+        };
+        // the key is an integer rowid, so the ulid cannot go there
+        info!("storing file {} uploaded at {}", filename, timestamp);
         self.connection
             .execute(
-                "INSERT INTO files (key, file_name, content) VALUES (?1, ?2)",
-                params![timestamp.to_string(), filename, compressed_data],
+                "INSERT INTO files
+                    (file_name, content, size_bytes, uploaded_at, source_host, hash, nonce)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    filename,
+                    compressed_data,
+                    size,
+                    timestamp.to_string(),
+                    source_host,
+                    hash,
+                    nonce
+                ],
             )
-            .unwrap();
+            .map_err(|e| e.to_string())?;
 
         Ok(())
     }
 
-    fn get_files(&self) -> Result<Vec<String>, rusqlite::Error> {
+    // stores a file pulled from a peer as it was sent, still compressed. the
+    // content is checked against the hash the peer listed before it is kept
+    fn store_file(&self, file: &SharedFile, content: &[u8]) -> Result<(), String> {
+        check_file_name(&file.name)?;
+        let decoder = zstd::stream::read::Decoder::new(content).map_err(|e| e.to_string())?;
+        // one byte over the limit is enough to know it is too large
+        let mut decoder = decoder.take(self.max_upload_bytes + 1);
+        let mut hasher = Sha256::new();
+        let mut buf = [0u8; 64 * 1024];
+        let mut size = 0u64;
+        loop {
+            let n = decoder.read(&mut buf).map_err(|e| e.to_string())?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+        if size > self.max_upload_bytes {
+            return Err(format!(
+                "file {} exceeds limit {}",
+                file.name,
+                format_bytes(self.max_upload_bytes)
+            ));
+        }
+        if hex(&hasher.finalize()) != file.hash {
+            return Err(format!("file {} does not match its hash", file.name));
+        }
+
+        info!("storing file {} pulled from a peer", file.name);
+        let nonce = self.row_nonce();
+        let content = self.seal_bytes(nonce.as_deref(), FILE_COLUMN, content.to_vec());
+        self.connection
+            .execute(
+                "INSERT INTO files
+                    (file_name, content, size_bytes, uploaded_at, source_host, hash, nonce)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    file.name,
+                    content,
+                    size,
+                    file.uploaded_at,
+                    file.source_host,
+                    file.hash,
+                    nonce
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    // the compressed content of a file, as it is sent to peers
+    fn file_content(&self, file_name: &str) -> Result<Vec<u8>, rusqlite::Error> {
+        self.connection.query_row(
+            "SELECT content, nonce FROM files WHERE file_name = ?1",
+            params![file_name],
+            |row| {
+                let nonce: Option<Vec<u8>> = row.get(1)?;
+                self.open_bytes(row, 0, nonce.as_deref(), FILE_COLUMN)
+                    .map(Option::unwrap_or_default)
+            },
+        )
+    }
+
+    // decompresses a stored file straight from its blob into the target path,
+    // so the whole file is never held in memory, unless it is encrypted and
+    // has to be decrypted as a whole first. this relies on the cli and the
+    // daemon sharing a filesystem, fetching a file from another device will
+    // need it sent over the network instead. raw writes the compressed
+    // content as it is, e.g. to keep a backup without compressing it again
+    fn download_file(
+        &self,
+        file_name: &str,
+        download_path: &str,
+        raw: bool,
+    ) -> Result<(String, u64), String> {
+        // names stored before they were checked are still checked here
+        check_file_name(file_name)?;
+        let (rowid, encrypted): (i64, bool) = self
+            .connection
+            .query_row(
+                "SELECT key, nonce IS NOT NULL FROM files WHERE file_name = ?1",
+                params![file_name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => format!("no file named {}", file_name),
+                e => e.to_string(),
+            })?;
+        let compressed: Box<dyn Read + '_> = if encrypted {
+            let content = self.file_content(file_name).map_err(|e| e.to_string())?;
+            Box::new(std::io::Cursor::new(content))
+        } else {
+            let blob = self
+                .connection
+                .blob_open(DatabaseName::Main, "files", "content", rowid, true)
+                .map_err(|e| e.to_string())?;
+            Box::new(blob)
+        };
+        let mut content: Box<dyn Read + '_> = if raw {
+            compressed
+        } else {
+            Box::new(zstd::stream::read::Decoder::new(compressed).map_err(|e| e.to_string())?)
+        };
+
+        let mut path = PathBuf::from(download_path);
+        if path.is_dir() && raw {
+            path.push(format!("{}.zst", file_name));
+        } else if path.is_dir() {
+            path.push(file_name);
+        }
+        let mut file = fs::File::create(&path).map_err(|e| e.to_string())?;
+        match std::io::copy(&mut content, &mut file) {
+            Ok(bytes) => Ok((path.to_string_lossy().to_string(), bytes)),
+            Err(e) => {
+                // do not leave half a file behind
+                let _ = fs::remove_file(&path);
+                Err(e.to_string())
+            }
+        }
+    }
+
+    fn counts(&self) -> Result<(u64, u64), rusqlite::Error> {
+        let query = format!(
+            "SELECT (SELECT COUNT(*) FROM clipboard WHERE {}), (SELECT COUNT(*) FROM files)",
+            NOT_EXPIRED
+        );
+        self.connection
+            .query_row(&query, [], |row| Ok((row.get(0)?, row.get(1)?)))
+    }
+
+    fn get_files(&self) -> Result<Vec<FileInfo>, rusqlite::Error> {
         let query = "
-        SELECT f.file_name
-        FROM files f;
+        SELECT f.file_name, f.size_bytes, f.uploaded_at, f.source_host, f.hash
+        FROM files f
+        ORDER BY f.file_name;
         ";
 
         let mut statement = self
@@ -165,70 +782,143 @@ impl Database {
             .prepare(query)
             .expect("unable to prepare query");
 
-        let res: Result<Vec<String>, rusqlite::Error> = statement
-            .query_map([], |row| row.get::<usize, String>(0))?
+        let res: Result<Vec<FileInfo>, rusqlite::Error> = statement
+            .query_map([], |row| {
+                let uploaded_at: Option<String> = row.get(2)?;
+                Ok(FileInfo {
+                    name: row.get(0)?,
+                    size_bytes: row.get(1)?,
+                    uploaded_at: uploaded_at.and_then(|u| Ulid::from_string(&u).ok()),
+                    source_host: row.get(3)?,
+                    hash: row.get(4)?,
+                })
+            })?
             .collect();
 
-        match res {
-            Ok(res) => return Ok(res),
-            Err(e) => return Err(e),
-        }
+        res
     }
 
-    fn get_history(&self) -> Result<Vec<String>, rusqlite::Error> {
-        let query = "
-            SELECT c.text_data
-            FROM clipboard c
-            ORDER BY key DESC
-            LIMIT 20;
-        ";
+    // a page of the history, newest first, and whether older entries follow
+    fn get_history(
+        &self,
+        limit: usize,
+        offset: usize,
+        thumbnails: bool,
+    ) -> Result<(Vec<HistoryEntry>, bool), rusqlite::Error> {
+        let query = format!(
+            "SELECT c.text_data, c.width, c.height, c.nonce, c.thumbnail, c.origin
+             FROM clipboard c
+             WHERE {}
+             ORDER BY key DESC
+             LIMIT ?1 OFFSET ?2;",
+            NOT_EXPIRED
+        );
 
         let mut statement = self
             .connection
-            .prepare(query)
+            .prepare(&query)
             .expect("failed to prepare query");
 
         let result = statement
-            .query_map(params![], |row| {
-                let name: Option<String> = row.get::<usize, Option<String>>(0)?;
-                Ok(name.unwrap_or_else(|| "image".to_string()))
+            // one more than asked for, to tell whether there is another page
+            .query_map(params![limit.saturating_add(1), offset], |row| {
+                let nonce: Option<Vec<u8>> = row.get(3)?;
+                let nonce = nonce.as_deref();
+                let origin: Option<String> = row.get(5)?;
+                match self.open_text(row, 0, nonce, TEXT_COLUMN)? {
+                    Some(name) => Ok(HistoryEntry {
+                        name,
+                        thumbnail: None,
+                        origin,
+                    }),
+                    None => {
+                        let width: Option<usize> = row.get(1)?;
+                        let height: Option<usize> = row.get(2)?;
+                        let thumbnail = if thumbnails {
+                            self.open_bytes(row, 4, nonce, THUMBNAIL_COLUMN)?
+                        } else {
+                            None
+                        };
+                        Ok(HistoryEntry {
+                            name: format!(
+                                "image({}x{})",
+                                width.unwrap_or_default(),
+                                height.unwrap_or_default()
+                            ),
+                            thumbnail,
+                            origin,
+                        })
+                    }
+                }
             })?
-            .collect::<Result<Vec<String>, rusqlite::Error>>();
+            .collect::<Result<Vec<HistoryEntry>, rusqlite::Error>>();
 
-        result
+        result.map(|mut entries| {
+            let more = entries.len() > limit;
+            entries.truncate(limit);
+            (entries, more)
+        })
     }
 
+    // callers wrap this and save_image in a transaction
+    #[allow(clippy::too_many_arguments)]
     fn save_text(
         &self,
         text: String,
+        html: Option<String>,
+        mime: Option<String>,
         timestamp: Ulid,
-        local: bool,
+        origin: Option<Origin>,
+        expires_at: Option<u64>,
     ) -> Result<usize, rusqlite::Error> {
-        if local {
-            self.inc_self_counter()?;
-        }
+        let (host, counter) = origin.map(|o| (o.host, o.counter)).unzip();
+        let nonce = self.row_nonce();
+        let text = self.seal_text(nonce.as_deref(), TEXT_COLUMN, text);
+        let html = html.map(|html| self.seal_text(nonce.as_deref(), HTML_COLUMN, html));
         let query = "
-            INSERT INTO clipboard (key, text_data) VALUES (?1, ?2)
+            INSERT INTO clipboard
+                (key, text_data, html_data, origin, counter, mime, nonce, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
         ";
         let mut statement = self
             .connection
             .prepare(query)
             .expect("unable to prepare query");
 
-        statement.execute(params![timestamp.to_string(), text])
+        statement.execute(params![
+            timestamp.to_string(),
+            text,
+            html,
+            host,
+            counter,
+            mime,
+            nonce,
+            expires_at
+        ])
     }
 
     fn save_image(
         &self,
         image: SerializableImage,
+        mime: Option<String>,
         timestamp: Ulid,
-        local: bool,
+        origin: Option<Origin>,
+        expires_at: Option<u64>,
     ) -> Result<usize, rusqlite::Error> {
-        if local {
-            self.inc_self_counter()?;
-        }
+        let (host, counter) = origin.map(|o| (o.host, o.counter)).unzip();
+        let nonce = self.row_nonce();
+        let rgba = rgba_image(&image);
+        let thumbnail = rgba
+            .as_ref()
+            .and_then(thumbnail)
+            .map(|png| self.seal_bytes(nonce.as_deref(), THUMBNAIL_COLUMN, png));
+        let png = rgba.and_then(|rgba| encode_png(&image::DynamicImage::ImageRgba8(rgba)));
+        let bytes = self.seal_bytes(nonce.as_deref(), IMAGE_COLUMN, png.unwrap_or(image.bytes));
         let query = "
-            INSERT INTO clipboard (key, width, height, image_content) VALUES (?1, ?2, ?3, ?4)
+            INSERT INTO clipboard
+                (key, width, height, image_content, origin, counter, mime, thumbnail, nonce,
+                    expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
         ";
         let mut statement = self
             .connection
@@ -239,99 +929,587 @@ impl Database {
             timestamp.to_string(),
             image.width,
             image.height,
-            image.bytes
+            bytes,
+            host,
+            counter,
+            mime,
+            thumbnail,
+            nonce,
+            expires_at
         ])
     }
 
-    fn read_clipboard(&self, offset: usize) -> Result<ClipboardEntry, rusqlite::Error> {
-        let query = "
-            SELECT c.text_data, c.width, c.height, c.image_content
-            FROM clipboard c
-            ORDER BY key DESC
-            LIMIT 1 OFFSET ?;
-        ";
+    // deletes the entries whose time is up, every PURGE_INTERVAL through
+    // PurgeExpired on the writer. old tombstones go too
+    fn purge_expired(&self) {
+        let now = unix_now();
+        match self
+            .connection
+            .execute("DELETE FROM clipboard WHERE expires_at <= ?1", params![now])
+        {
+            Ok(0) => {}
+            Ok(purged) => info!("deleted {} expired entries", purged),
+            Err(e) => error!("failed to delete expired entries: {}", e),
+        }
+        match self.connection.execute(
+            "DELETE FROM tombstones WHERE deleted_at <= ?1",
+            params![now.saturating_sub(TOMBSTONE_RETENTION_SECS)],
+        ) {
+            Ok(0) => {}
+            Ok(purged) => info!("forgot {} old tombstones", purged),
+            Err(e) => error!("failed to delete old tombstones: {}", e),
+        }
+    }
+
+    fn is_deleted(&self, key: &str) -> Result<bool, rusqlite::Error> {
+        self.connection
+            .query_row(
+                "SELECT 1 FROM tombstones WHERE key = ?1",
+                params![key],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|found| found.is_some())
+    }
+
+    // deletes an entry and keeps a tombstone for it, which is shared like an
+    // entry. origin is None for a local delete, which takes the next counter
+    // of our own. returns the origin of the tombstone, None if the entry was
+    // deleted before. callers wrap this in a transaction
+    fn delete_entry(
+        &self,
+        key: Ulid,
+        origin: Option<Origin>,
+    ) -> Result<Option<Origin>, rusqlite::Error> {
+        let key = key.to_string();
+        if self.is_deleted(&key)? {
+            return Ok(None);
+        }
+        let origin = match origin {
+            Some(origin) => origin,
+            None => self.inc_self_counter()?,
+        };
+        self.connection.execute(
+            "INSERT INTO tombstones (key, origin, counter, deleted_at) VALUES (?1, ?2, ?3, ?4)",
+            params![key, origin.host, origin.counter, unix_now()],
+        )?;
+        self.connection
+            .execute("DELETE FROM clipboard WHERE key = ?1", params![key])?;
+        Ok(Some(origin))
+    }
+
+    // the key of the entry at an offset, newest first like paste
+    fn key_at(&self, offset: usize) -> Result<Ulid, rusqlite::Error> {
+        let query = format!(
+            "SELECT key FROM clipboard WHERE {} ORDER BY key DESC LIMIT 1 OFFSET ?1",
+            NOT_EXPIRED
+        );
+        let key: String = self
+            .connection
+            .query_row(&query, params![offset], |row| row.get(0))?;
+        Ulid::from_string(&key).map_err(|e| conversion_error(0, e.to_string()))
+    }
+
+    // tombstones the holder of `clock` has not seen yet, all of them for an
+    // empty clock
+    fn get_tombstones(&self, clock: &Clock) -> Result<Vec<ClipboardRecord>, rusqlite::Error> {
+        let query = format!(
+            "SELECT key, origin, counter FROM tombstones WHERE counter > {} ORDER BY key DESC",
+            seen_counter(clock)
+        );
+        let params: Vec<_> = clock
+            .iter()
+            .flat_map(|(k, v)| vec![k as &dyn rusqlite::ToSql, v as &dyn rusqlite::ToSql])
+            .collect();
+
+        let mut statement = self.connection.prepare(&query)?;
+        let rows = statement.query_map(&params[..], |row| {
+            Ok(ClipboardRecord::tombstone(
+                row.get(0)?,
+                Origin {
+                    host: row.get(1)?,
+                    counter: row.get(2)?,
+                },
+            ))
+        })?;
+        rows.collect()
+    }
+
+    // the current clipboard is the entry with the largest key, last writer
+    // wins. keys are ulids, so that is the latest copy on any device, and
+    // every node that has the same entries agrees on it however they arrived.
+    // copies within the same millisecond are ordered by the random part of
+    // the ulid, arbitrary but the same everywhere
+    fn current_entry(&self) -> Result<(ClipboardEntry, Option<String>), rusqlite::Error> {
+        self.read_clipboard(0)
+    }
+
+    // what went wrong reading the entry at an offset, in words for the cli
+    fn read_failure(&self, offset: usize, e: rusqlite::Error) -> String {
+        match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                let available: usize = self
+                    .connection
+                    .query_row(
+                        &format!("SELECT COUNT(*) FROM clipboard WHERE {}", NOT_EXPIRED),
+                        [],
+                        |row| row.get(0),
+                    )
+                    .unwrap_or_default();
+                if available == 0 {
+                    "the clipboard history is empty".to_string()
+                } else {
+                    format!(
+                        "no clipboard entry at offset {} (only {} available)",
+                        offset, available
+                    )
+                }
+            }
+            e => {
+                error!("failed to read db: {}", e);
+                format!("failed to read clipboard entry: {}", e)
+            }
+        }
+    }
+
+    fn read_clipboard(
+        &self,
+        offset: usize,
+    ) -> Result<(ClipboardEntry, Option<String>), rusqlite::Error> {
+        let query = format!(
+            "SELECT c.text_data, c.width, c.height, c.image_content, c.html_data, c.mime, c.nonce
+             FROM clipboard c
+             WHERE {}
+             ORDER BY key DESC
+             LIMIT 1 OFFSET ?;",
+            NOT_EXPIRED
+        );
 
         let mut statement = self
             .connection
-            .prepare(query)
+            .prepare(&query)
             .expect("unable to prepare query");
 
         statement.query_row(params![offset], |row| {
-            let text: Option<String> = row.get::<usize, Option<String>>(0)?;
+            let nonce: Option<Vec<u8>> = row.get(6)?;
+            let nonce = nonce.as_deref();
+            let text = self.open_text(row, 0, nonce, TEXT_COLUMN)?;
             let width: Option<usize> = row.get::<usize, Option<usize>>(1)?;
             let height: Option<usize> = row.get::<usize, Option<usize>>(2)?;
-            let content: Option<Vec<u8>> = row.get::<usize, Option<Vec<u8>>>(3)?;
-
-            println!("{:?} {:?} {:?} {:?}", text, width, height, &content);
-            if let Some(t) = text {
-                return Ok(ClipboardEntry::Text(t));
-            } else if let (Some(w), Some(h), Some(img)) = (width, height, &content) {
-                return Ok(ClipboardEntry::Image(SerializableImage {
-                    width: w,
-                    height: h,
-                    bytes: img.clone(),
-                }));
+            let content = self.open_bytes(row, 3, nonce, IMAGE_COLUMN)?;
+            let html = self.open_text(row, 4, nonce, HTML_COLUMN)?;
+            let mime: Option<String> = row.get::<usize, Option<String>>(5)?;
+
+            trace!(
+                "row with {} bytes of text and {} bytes of image",
+                text.as_ref().map_or(0, String::len),
+                content.as_ref().map_or(0, Vec::len)
+            );
+            if let (Some(html), Some(alt_text)) = (html, &text) {
+                Ok((
+                    ClipboardEntry::Html {
+                        html,
+                        alt_text: alt_text.clone(),
+                    },
+                    mime,
+                ))
+            } else if let Some(t) = text {
+                Ok((ClipboardEntry::Text(t), mime))
+            } else if let (Some(w), Some(h), Some(img)) = (width, height, content) {
+                Ok((
+                    ClipboardEntry::Image(image_from_column(3, w, h, img)?),
+                    mime,
+                ))
             } else {
                 Err(rusqlite::Error::QueryReturnedNoRows)
             }
         })
     }
 
-    pub fn get_recent(&self, limit: u64) -> Result<Vec<(ClipboardEntry, String)>, rusqlite::Error> {
-        let query = "
-            SELECT c.key, c.text_data, c.width, c.height, c.image_content
-            FROM clipboard c
-            ORDER BY c.key DESC
-            LIMIT ?;
-        ";
+    fn record_from_row(&self, row: &rusqlite::Row) -> Result<ClipboardRecord, rusqlite::Error> {
+        let nonce: Option<Vec<u8>> = row.get(9)?;
+        let nonce = nonce.as_deref();
+        let key: String = row.get(0)?;
+        let text = self.open_text(row, 1, nonce, TEXT_COLUMN)?;
+        let width: Option<usize> = row.get(2)?;
+        let height: Option<usize> = row.get(3)?;
+        let content = self.open_bytes(row, 4, nonce, IMAGE_COLUMN)?;
+        let host: Option<String> = row.get(5)?;
+        let counter: Option<u64> = row.get(6)?;
+        let html = self.open_text(row, 7, nonce, HTML_COLUMN)?;
+        let mime: Option<String> = row.get(8)?;
+        let expires_at: Option<u64> = row.get(10)?;
+
+        let entry = if let (Some(html), Some(alt_text)) = (html, &text) {
+            ClipboardEntry::Html {
+                html,
+                alt_text: alt_text.clone(),
+            }
+        } else if let Some(t) = text {
+            ClipboardEntry::Text(t)
+        } else if let (Some(w), Some(h), Some(img)) = (width, height, content) {
+            ClipboardEntry::Image(image_from_column(4, w, h, img)?)
+        } else {
+            // Gracefully skip invalid row
+            return Err(rusqlite::Error::InvalidQuery);
+        };
+
+        let origin = match (host, counter) {
+            (Some(host), Some(counter)) => Some(Origin { host, counter }),
+            _ => None,
+        };
+
+        Ok(ClipboardRecord {
+            key,
+            entry,
+            origin,
+            mime,
+            expires_at,
+            deleted: false,
+        })
+    }
+
+    // writes the clock and every entry, oldest first, as one json document of
+    // the form {"clock": {..}, "entries": [..]}. entries are written one at a
+    // time, so a long history is never held in memory at once
+    fn export_all<W: Write>(&self, out: &mut W, files: bool) -> Result<(usize, usize), String> {
+        let clock = self.load_clock().map_err(|e| e.to_string())?;
+        let host = self.self_host().map_err(|e| e.to_string())?;
+        let query = format!(
+            "SELECT c.key, c.text_data, c.width, c.height, c.image_content, c.origin, c.counter,
+                c.html_data, c.mime, c.nonce, c.expires_at
+             FROM clipboard c
+             WHERE {}
+             ORDER BY c.key;",
+            NOT_EXPIRED
+        );
+        let mut statement = self.connection.prepare(&query).map_err(|e| e.to_string())?;
+        let rows = statement
+            .query_map([], |row| self.record_from_row(row))
+            .map_err(|e| e.to_string())?;
+
+        write!(
+            out,
+            "{{\"version\":{},\"host\":{},\"exported_at\":{},\"clock\":",
+            MIGRATIONS.len(),
+            serde_json::to_string(&host).map_err(|e| e.to_string())?,
+            unix_now()
+        )
+        .map_err(|e| e.to_string())?;
+        serde_json::to_writer(&mut *out, &clock).map_err(|e| e.to_string())?;
+        write!(out, ",\"entries\":[").map_err(|e| e.to_string())?;
+        let mut exported = 0;
+        for row in rows {
+            let record = match row {
+                Ok(record) => record,
+                // rows with nothing readable in them are skipped, as in get_recent
+                Err(rusqlite::Error::InvalidQuery) => continue,
+                Err(e) => return Err(e.to_string()),
+            };
+            if exported > 0 {
+                write!(out, ",").map_err(|e| e.to_string())?;
+            }
+            serde_json::to_writer(&mut *out, &record).map_err(|e| e.to_string())?;
+            exported += 1;
+        }
+        // so an import deletes what was deleted here. they are not counted
+        let tombstones = self
+            .get_tombstones(&Clock::new())
+            .map_err(|e| e.to_string())?;
+        for (i, tombstone) in tombstones.iter().enumerate() {
+            if exported > 0 || i > 0 {
+                write!(out, ",").map_err(|e| e.to_string())?;
+            }
+            serde_json::to_writer(&mut *out, tombstone).map_err(|e| e.to_string())?;
+        }
+        write!(out, "]").map_err(|e| e.to_string())?;
+
+        let mut exported_files = 0;
+        if files {
+            write!(out, ",\"files\":[").map_err(|e| e.to_string())?;
+            for info in self.get_files().map_err(|e| e.to_string())? {
+                // like for peers, files from before hashes were recorded are left out
+                let (Some(hash), Some(size_bytes)) = (info.hash, info.size_bytes) else {
+                    warn!("not exporting {}, it has no hash", info.name);
+                    continue;
+                };
+                let content = self.file_content(&info.name).map_err(|e| e.to_string())?;
+                let file = ExportedFile {
+                    file: SharedFile {
+                        hash,
+                        size_bytes,
+                        uploaded_at: info.uploaded_at.map(|u| u.to_string()),
+                        source_host: info.source_host,
+                        name: info.name,
+                    },
+                    content: STANDARD.encode(content),
+                };
+                if exported_files > 0 {
+                    write!(out, ",").map_err(|e| e.to_string())?;
+                }
+                serde_json::to_writer(&mut *out, &file).map_err(|e| e.to_string())?;
+                exported_files += 1;
+            }
+            write!(out, "]").map_err(|e| e.to_string())?;
+        }
+        writeln!(out, "}}").map_err(|e| e.to_string())?;
+        out.flush().map_err(|e| e.to_string())?;
+        Ok((exported, exported_files))
+    }
+
+    // exports into the file at `path`, with the files and compressed when it
+    // ends in .zst, or into memory to be sent back over the socket when there
+    // is none. returns how many entries and files were exported
+    fn export(&self, path: Option<&str>) -> Result<(usize, usize, Option<String>), String> {
+        match path {
+            Some(path) => {
+                let mut file = BufWriter::new(fs::File::create(path).map_err(|e| e.to_string())?);
+                let exported = if path.ends_with(".zst") {
+                    self.export_compressed(file)
+                } else {
+                    self.export_all(&mut file, true)
+                };
+                if exported.is_err() {
+                    let _ = fs::remove_file(path);
+                }
+                let (entries, files) = exported?;
+                Ok((entries, files, None))
+            }
+            None => {
+                let mut json = Vec::new();
+                let (entries, _) = self.export_all(&mut json, false)?;
+                let json = String::from_utf8(json).map_err(|e| e.to_string())?;
+                Ok((entries, 0, Some(json)))
+            }
+        }
+    }
+
+    fn export_compressed<W: Write>(&self, out: W) -> Result<(usize, usize), String> {
+        let mut encoder = zstd::stream::write::Encoder::new(out, self.compression_level)
+            .map_err(|e| e.to_string())?;
+        let exported = self.export_all(&mut encoder, true)?;
+        encoder
+            .finish()
+            .and_then(|mut out| out.flush())
+            .map_err(|e| e.to_string())?;
+        Ok(exported)
+    }
+
+    // adds the entries and files of an export that we do not have yet, by
+    // key and by name, deletes the ones it has tombstones for, and merges its
+    // clock into ours keeping the higher counter of each host. with overwrite,
+    // files that differ from the one of the same name here replace it.
+    // returns how many entries and files were added and how many were
+    // already present
+    fn import(&self, path: &str, overwrite: bool) -> Result<(usize, usize, usize), String> {
+        let file = fs::File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+        let mut file = BufReader::new(file);
+        let compressed = file
+            .fill_buf()
+            .map_err(|e| format!("cannot read {}: {}", path, e))?
+            .starts_with(ZSTD_MAGIC);
+        let reader: Box<dyn Read> = if compressed {
+            Box::new(zstd::stream::read::Decoder::with_buffer(file).map_err(|e| e.to_string())?)
+        } else {
+            Box::new(file)
+        };
+        let exported: ExportFile = serde_json::from_reader(reader)
+            .map_err(|e| format!("{} is not a slate export: {}", path, e))?;
+        // it may hold entries or files this slate would get wrong
+        if exported.version > MIGRATIONS.len() {
+            return Err(format!(
+                "{} is from a newer slate (schema version {}, this one knows up to {}), update slate",
+                path,
+                exported.version,
+                MIGRATIONS.len()
+            ));
+        }
+
+        let transaction = self
+            .connection
+            .unchecked_transaction()
+            .map_err(|e| e.to_string())?;
+        let (mut added, mut skipped) = (0, 0);
+        for record in exported.entries {
+            let Ok(key) = Ulid::from_string(&record.key) else {
+                warn!("skipping imported entry with invalid key {}", record.key);
+                skipped += 1;
+                continue;
+            };
+            if record.deleted {
+                match record.origin {
+                    Some(origin) => {
+                        self.delete_entry(key, Some(origin))
+                            .map_err(|e| e.to_string())?;
+                    }
+                    None => warn!("skipping imported tombstone {} without origin", key),
+                }
+                continue;
+            }
+            let exists = transaction
+                .query_row(
+                    "SELECT 1 FROM clipboard WHERE key = ?1",
+                    params![record.key],
+                    |_| Ok(()),
+                )
+                .optional()
+                .map_err(|e| e.to_string())?
+                .is_some();
+            let expired = record.expires_at.is_some_and(|at| at <= unix_now());
+            let deleted = self.is_deleted(&record.key).map_err(|e| e.to_string())?;
+            if exists || expired || deleted {
+                skipped += 1;
+                continue;
+            }
+            let (mime, origin, expires_at) = (record.mime, record.origin, record.expires_at);
+            match record.entry {
+                ClipboardEntry::Text(text) => {
+                    self.save_text(text, None, mime, key, origin, expires_at)
+                }
+                ClipboardEntry::Html { html, alt_text } => {
+                    self.save_text(alt_text, Some(html), mime, key, origin, expires_at)
+                }
+                ClipboardEntry::Image(image) => {
+                    self.save_image(image, mime, key, origin, expires_at)
+                }
+            }
+            .map_err(|e| e.to_string())?;
+            added += 1;
+        }
+
+        let mut files = 0;
+        for ExportedFile { file, content } in exported.files {
+            let existing: Option<Option<String>> = transaction
+                .query_row(
+                    "SELECT hash FROM files WHERE file_name = ?1",
+                    params![file.name],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(|e| e.to_string())?;
+            match existing {
+                None => {}
+                Some(hash) if overwrite && hash.as_ref() != Some(&file.hash) => {
+                    info!("replacing file {} with the imported one", file.name);
+                    transaction
+                        .execute("DELETE FROM files WHERE file_name = ?1", params![file.name])
+                        .map_err(|e| e.to_string())?;
+                }
+                Some(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            }
+            let content = STANDARD
+                .decode(content)
+                .map_err(|e| format!("file {} is not base64: {}", file.name, e))?;
+            self.store_file(&file, &content)?;
+            files += 1;
+        }
+
+        let mut clock = self.load_clock().map_err(|e| e.to_string())?;
+        merge_clock(&mut clock, &exported.clock);
+        self.sync_clock(&clock).map_err(|e| e.to_string())?;
+        transaction.commit().map_err(|e| e.to_string())?;
+        Ok((added, files, skipped))
+    }
+
+    pub fn get_recent(
+        &self,
+        limit: u64,
+        offset: u64,
+    ) -> Result<Vec<ClipboardRecord>, rusqlite::Error> {
+        let query = format!(
+            "SELECT c.key, c.text_data, c.width, c.height, c.image_content, c.origin, c.counter,
+                c.html_data, c.mime, c.nonce, c.expires_at
+             FROM clipboard c
+             WHERE {}
+             ORDER BY c.key DESC
+             LIMIT ?1 OFFSET ?2;",
+            NOT_EXPIRED
+        );
 
         let mut statement = self
             .connection
-            .prepare(query)
+            .prepare(&query)
             .expect("unable to prepare query");
 
-        let rows = statement.query_map(params![limit], |row| {
-            let key: String = row.get(0)?;
-            let text: Option<String> = row.get(1)?;
-            let width: Option<usize> = row.get(2)?;
-            let height: Option<usize> = row.get(3)?;
-            let content: Option<Vec<u8>> = row.get(4)?;
+        let rows = statement.query_map(params![limit, offset], |row| self.record_from_row(row))?;
 
-            let entry = if let Some(t) = text {
-                ClipboardEntry::Text(t)
-            } else if let (Some(w), Some(h), Some(img)) = (width, height, content) {
-                ClipboardEntry::Image(SerializableImage {
-                    width: w,
-                    height: h,
-                    bytes: img,
-                })
-            } else {
-                // Gracefully skip invalid row
-                return Err(rusqlite::Error::InvalidQuery);
-            };
+        // Collecting into Vec
+        let mut records = rows.collect::<Result<Vec<_>, _>>()?;
+        // a peer pulling this has never synced with us, so it gets every
+        // tombstone along with the first page
+        if offset == 0 {
+            records.extend(self.get_tombstones(&Clock::new())?);
+        }
+        Ok(records)
+    }
 
-            Ok((entry, key))
-        })?;
+    // entries the holder of `clock` has not seen yet, i.e. whose counter is
+    // past what the clock records for their origin. rows without an origin
+    // are only ever shared through the full pull in `get_recent`
+    // deletes count on the clock like copies, so their tombstones come along
+    pub fn get_since(&self, clock: &Clock) -> Result<Vec<ClipboardRecord>, rusqlite::Error> {
+        let query = format!(
+            "SELECT c.key, c.text_data, c.width, c.height, c.image_content, c.origin, c.counter,
+                c.html_data, c.mime, c.nonce, c.expires_at
+             FROM clipboard c
+             WHERE c.origin IS NOT NULL AND c.counter > {} AND {}
+             ORDER BY c.key DESC",
+            seen_counter(clock),
+            NOT_EXPIRED
+        );
 
-        // Collecting into Vec
-        rows.collect()
+        let params: Vec<_> = clock
+            .iter()
+            .flat_map(|(k, v)| vec![k as &dyn rusqlite::ToSql, v as &dyn rusqlite::ToSql])
+            .collect();
+
+        let mut statement = self
+            .connection
+            .prepare(&query)
+            .expect("unable to prepare query");
+
+        let rows = statement.query_map(&params[..], |row| self.record_from_row(row))?;
+        let mut records = rows.collect::<Result<Vec<_>, _>>()?;
+        records.extend(self.get_tombstones(clock)?);
+        Ok(records)
     }
 
+    // makes host_name the one self row. after a rename the old name stays
+    // on as a plain row, like a peer's, since entries still carry it
     pub fn insert_self(&self, host_name: String) -> Result<(), rusqlite::Error> {
-        let sql = "
-            INSERT INTO clock (key, self, time) VALUES (?1, TRUE, 0)
-            ON CONFLICT(key) DO NOTHING
-        ";
-
-        self.connection.execute(&sql, params![host_name])?;
-        Ok(())
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute(
+            "INSERT INTO clock (key, self, time) VALUES (?1, TRUE, 0)
+             ON CONFLICT(key) DO UPDATE SET self = TRUE",
+            params![host_name],
+        )?;
+        let demoted = transaction.execute(
+            "UPDATE clock SET self = FALSE WHERE self = TRUE AND key != ?1",
+            params![host_name],
+        )?;
+        if demoted > 0 {
+            info!(
+                "host name is now {}, keeping the old one as a peer",
+                host_name
+            );
+        }
+        transaction.commit()
     }
 
-    pub async fn listen(self, mut rx: Receiver<DBMessage>) {
-        println!("db started!");
-        while let Some(msg) = rx.recv().await {
+    // blocks the thread it runs on, so a slow command does not hold up the
+    // rest of the daemon
+    pub fn listen(self, mut rx: Receiver<DBMessage>) {
+        info!("db started!");
+        // replies are dropped when the asker is gone, e.g. an http handler
+        // whose peer timed out and hung up
+        while let Some(msg) = rx.blocking_recv() {
             let tx = msg.sender;
             let cmd = msg.cmd;
+            // nothing below awaits, so the span is left before the next message
+            let _span = debug_span!("db", command = cmd.name()).entered();
             use DBCommand::*;
             match cmd {
                 Upload {
@@ -343,148 +1521,556 @@ impl Database {
                     let result = self.upload_file(&file_name, &file_path, timestamp, local);
                     match result {
                         Ok(()) => {
-                            tx.send(Ok(Response::Success))
-                                .expect("failed to send response");
+                            let _ = tx.send(Ok(Response::Success));
+                        }
+                        Err(e) => {
+                            let _ = tx.send(Err(e));
+                        }
+                    }
+                }
+                Download {
+                    download_path,
+                    file_name,
+                    raw,
+                } => match self.download_file(&file_name, &download_path, raw) {
+                    Ok((path, bytes)) => {
+                        let _ = tx.send(Ok(Response::Downloaded { path, bytes }));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                },
+                Entry { offset } => {
+                    let result = match offset {
+                        Some(offset) => self.read_clipboard(offset),
+                        None => self.current_entry(),
+                    };
+                    match result {
+                        Ok((entry, _)) => {
+                            let _ = tx.send(Ok(Response::Entry { entry }));
                         }
                         Err(e) => {
-                            tx.send(Err(e.to_string()))
-                                .expect("failed to send response");
+                            let failure = self.read_failure(offset.unwrap_or_default(), e);
+                            let _ = tx.send(Err(failure));
                         }
                     }
                 }
+                PurgeExpired => {
+                    self.purge_expired();
+                    let _ = tx.send(Ok(Response::Success));
+                }
+                Counts => match self.counts() {
+                    Ok((entries, files)) => {
+                        let _ = tx.send(Ok(Response::Counts { entries, files }));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e.to_string()));
+                    }
+                },
                 ListFiles => {
                     let result = self.get_files();
                     match result {
                         Ok(x) => {
-                            tx.send(Ok(Response::Files { names: x }))
-                                .expect("failed to send response");
+                            let _ = tx.send(Ok(Response::Files { files: x }));
                         }
                         Err(e) => {
-                            tx.send(Err(e.to_string()))
-                                .expect("failed to send response");
+                            let _ = tx.send(Err(e.to_string()));
                         }
                     }
                 }
+                StoreFile { file, content } => match self.store_file(&file, &content) {
+                    Ok(()) => {
+                        let _ = tx.send(Ok(Response::Success));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                },
+                FileContent { file_name } => match self.file_content(&file_name) {
+                    Ok(content) => {
+                        let _ = tx.send(Ok(Response::FileContent { content }));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e.to_string()));
+                    }
+                },
                 CopyData {
                     data,
                     timestamp,
                     local,
+                    origin,
+                    mime,
+                    expires_at,
                 } => {
-                    let result = match data {
-                        ClipboardEntry::Text(t) => self.save_text(t, timestamp, local),
-                        ClipboardEntry::Image(i) => self.save_image(i, timestamp, local),
-                    };
+                    let deleted = self.is_deleted(&timestamp.to_string());
+                    // a local copy takes the next counter of our own, which
+                    // is only kept if the row is, or peers would wait for an
+                    // entry that never comes
+                    let transaction = self.connection.unchecked_transaction();
+                    let result = transaction.and_then(|t| {
+                        // it would only be purged right away
+                        if expires_at.is_some_and(|at| at <= unix_now()) {
+                            debug!("not storing {}, it has expired", timestamp);
+                            return Ok(None);
+                        }
+                        // a peer that has not seen the delete yet
+                        if matches!(deleted, Ok(true)) {
+                            debug!("not storing {}, it was deleted", timestamp);
+                            return Ok(None);
+                        }
+                        let origin = if local {
+                            Some(self.inc_self_counter()?)
+                        } else {
+                            origin
+                        };
+                        let stored = origin.clone();
+                        match data {
+                            ClipboardEntry::Text(t) => {
+                                self.save_text(t, None, mime, timestamp, origin, expires_at)
+                            }
+                            ClipboardEntry::Html { html, alt_text } => self.save_text(
+                                alt_text,
+                                Some(html),
+                                mime,
+                                timestamp,
+                                origin,
+                                expires_at,
+                            ),
+                            ClipboardEntry::Image(i) => {
+                                self.save_image(i, mime, timestamp, origin, expires_at)
+                            }
+                        }?;
+                        t.commit().map(|_| Some(stored))
+                    });
+                    match result {
+                        Ok(None) => {
+                            let _ = tx.send(Ok(Response::Skipped));
+                        }
+                        Ok(Some(origin)) => {
+                            let _ = tx.send(Ok(Response::Saved { origin }));
+                        }
+                        Err(e) => {
+                            let _ = tx.send(Err(e.to_string()));
+                        }
+                    }
+                }
+                Delete { key, origin } => {
+                    let result = self.connection.unchecked_transaction().and_then(|t| {
+                        let deleted = self.delete_entry(key, origin)?;
+                        t.commit().map(|_| deleted)
+                    });
                     match result {
-                        Ok(_) => {
-                            tx.send(Ok(Response::Success))
-                                .expect("failed to send response");
+                        Ok(origin) => {
+                            let _ = tx.send(Ok(Response::Deleted { origin }));
                         }
                         Err(e) => {
-                            tx.send(Err(e.to_string()))
-                                .expect("failed to send response");
+                            error!("failed to delete {}: {}", key, e);
+                            let _ = tx.send(Err(e.to_string()));
                         }
                     }
                 }
+                KeyAt { offset } => match self.key_at(offset) {
+                    Ok(key) => {
+                        let _ = tx.send(Ok(Response::Key { key }));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(self.read_failure(offset, e)));
+                    }
+                },
                 Paste {
                     offset,
                     mut clipboard,
                 } => {
-                    let result = self.read_clipboard(offset);
-                    let mut completed = true;
-                    if result.is_ok() {
-                        let r = result.unwrap();
-                        use ClipboardEntry::*;
-                        match r {
-                            Image(i) => {
-                                let i = i.into();
-                                if (clipboard.inner.set_image(i)).is_err() {
-                                    println!("failed to set image");
-                                    completed = false;
-                                }
+                    let result = match offset {
+                        Some(offset) => self.read_clipboard(offset),
+                        None => self.current_entry(),
+                    };
+                    match result {
+                        // text offered under a specific type (e.g. a uri list) goes back
+                        // out under that type when the platform lets us
+                        Ok((entry, mime)) => match clipboard.write(&entry, mime.as_deref()) {
+                            Ok(()) => {
+                                let preview = entry.preview();
+                                let _ = tx.send(Ok(Response::Pasted { preview, entry }));
                             }
-                            Text(t) => {
-                                if (clipboard.inner.set_text(t)).is_err() {
-                                    println!("failed to set text");
-                                    completed = false;
-                                }
+                            Err(e) => {
+                                error!("failed to set clipboard: {}", e);
+                                let _ = tx.send(Err("failed to paste".to_string()));
                             }
-                        };
-                    } else {
-                        println!("failed to read db");
-                        completed = false;
+                        },
+                        Err(e) => {
+                            let failure = self.read_failure(offset.unwrap_or_default(), e);
+                            let _ = tx.send(Err(failure));
+                        }
                     }
-
-                    if completed {
-                        tx.send(Ok(Response::Success))
-                            .expect("failed to send response");
-                    } else {
-                        tx.send(Err("failed to paste".to_string()))
-                            .expect("failed to send response");
+                }
+                ExportAll { path } => match self.export(path.as_deref()) {
+                    Ok((entries, files, json)) => {
+                        let _ = tx.send(Ok(Response::Exported {
+                            entries,
+                            files,
+                            json,
+                        }));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                },
+                ImportAll { path, overwrite } => match self.import(&path, overwrite) {
+                    Ok((added, files, skipped)) => {
+                        let _ = tx.send(Ok(Response::Imported {
+                            added,
+                            files,
+                            skipped,
+                        }));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                },
+                History {
+                    limit,
+                    offset,
+                    thumbnails,
+                } => {
+                    let limit = limit.unwrap_or(self.max_history);
+                    match self.get_history(limit, offset, thumbnails) {
+                        Ok((entries, more)) => {
+                            let _ = tx.send(Ok(Response::History { entries, more }));
+                        }
+                        Err(e) => {
+                            let _ = tx.send(Err(e.to_string()));
+                        }
                     }
                 }
-                History => match self.get_history() {
-                    Ok(x) => {
-                        tx.send(Ok(Response::History { names: x }))
-                            .expect("failed to send response");
+                Recent { length, offset } => match self.get_recent(length, offset) {
+                    Ok(res) => {
+                        let _ = tx.send(Ok(Response::Recent { values: res }));
                     }
                     Err(e) => {
-                        tx.send(Err(e.to_string()))
-                            .expect("failed to send response");
+                        let _ = tx.send(Err(e.to_string()));
                     }
                 },
-                Recent { length } => match self.get_recent(length) {
+                Since { clock } => match self.get_since(&clock) {
                     Ok(res) => {
-                        tx.send(Ok(Response::Recent { values: res }))
-                            .expect("failed to send response");
+                        let _ = tx.send(Ok(Response::Recent { values: res }));
                     }
                     Err(e) => {
-                        tx.send(Err(e.to_string()))
-                            .expect("failed to send response");
+                        let _ = tx.send(Err(e.to_string()));
                     }
                 },
                 InsertSelf { host_name } => match self.insert_self(host_name) {
                     Ok(()) => {
-                        tx.send(Ok(Response::Success))
-                            .expect("failed to send response");
+                        let _ = tx.send(Ok(Response::Success));
                     }
                     Err(e) => {
-                        tx.send(Err(e.to_string()))
-                            .expect("failed to send response");
+                        let _ = tx.send(Err(e.to_string()));
                     }
                 },
                 LoadClock => match self.load_clock() {
                     Ok(data) => {
-                        tx.send(Ok(Response::Clock { data }))
-                            .expect("failed to send response");
+                        let _ = tx.send(Ok(Response::Clock { data }));
                     }
                     Err(e) => {
-                        tx.send(Err(e.to_string()))
-                            .expect("failed to send response");
+                        let _ = tx.send(Err(e.to_string()));
                     }
                 },
                 SaveClock { clock } => match self.sync_clock(&clock) {
                     Ok(()) => {
-                        tx.send(Ok(Response::Success))
-                            .expect("failed to send response");
+                        let _ = tx.send(Ok(Response::Success));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e.to_string()));
+                    }
+                },
+                SaveNeighbors { peers } => match self.save_neighbors(&peers) {
+                    Ok(()) => {
+                        let _ = tx.send(Ok(Response::Success));
                     }
                     Err(e) => {
-                        tx.send(Err(e.to_string()))
-                            .expect("failed to send response");
+                        let _ = tx.send(Err(e.to_string()));
+                    }
+                },
+                LoadNeighbors => match self.load_neighbors() {
+                    Ok(peers) => {
+                        let _ = tx.send(Ok(Response::Neighbors { peers }));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e.to_string()));
+                    }
+                },
+                PruneClock { keep } => match self.prune_clock(&keep) {
+                    Ok(removed) => {
+                        debug!("pruned {} clock entries", removed);
+                        let _ = tx.send(Ok(Response::Success));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e.to_string()));
                     }
                 },
-                _ => {}
             }
         }
     }
 }
 
-pub struct ClipboardWrapper {
-    pub inner: arboard::Clipboard,
+// hands the commands that only read to the read connection and the rest to
+// the main one, so history and pastes are answered during a large upload or
+// import. a caller waits for its write before it reads, so it sees the write
+pub async fn route(
+    mut rx: Receiver<DBMessage>,
+    writer: mpsc::Sender<DBMessage>,
+    reader: mpsc::Sender<DBMessage>,
+) {
+    while let Some(msg) = rx.recv().await {
+        let connection = if msg.cmd.is_read() { &reader } else { &writer };
+        if connection.send(msg).await.is_err() {
+            error!("db connection is gone, not taking any more commands");
+            return;
+        }
+    }
+}
+
+// sql for the counter a clock has seen of a row's origin, 0 for hosts it does
+// not know. its parameters are the clock's hosts and counters, in pairs
+fn seen_counter(clock: &Clock) -> String {
+    if clock.is_empty() {
+        return "0".to_string();
+    }
+    let cases: Vec<_> = (0..clock.len())
+        .map(|i| format!("WHEN ?{} THEN ?{}", i * 2 + 1, i * 2 + 2))
+        .collect();
+    format!("COALESCE(CASE origin {} END, 0)", cases.join(" "))
 }
 
-impl Debug for ClipboardWrapper {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Clipboard")
+fn conversion_error(idx: usize, e: impl Into<String>) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(idx, Type::Blob, e.into().into())
+}
+
+// the raw rgba of an image, None if its bytes do not add up to its size
+fn rgba_image(image: &SerializableImage) -> Option<RgbaImage> {
+    let rgba = RgbaImage::from_raw(
+        u32::try_from(image.width).ok()?,
+        u32::try_from(image.height).ok()?,
+        image.bytes.clone(),
+    );
+    if rgba.is_none() {
+        warn!(
+            "image of {}x{} has {} bytes, storing it as it is",
+            image.width,
+            image.height,
+            image.bytes.len()
+        );
+    }
+    rgba
+}
+
+fn encode_png(image: &image::DynamicImage) -> Option<Vec<u8>> {
+    let mut png = Vec::new();
+    match image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png) {
+        Ok(()) => Some(png),
+        Err(e) => {
+            warn!("failed to encode png: {}", e);
+            None
+        }
+    }
+}
+
+// a png of the image scaled down to THUMBNAIL_SIZE, for previews in the
+// history. smaller images are kept at their size rather than blown up
+fn thumbnail(rgba: &RgbaImage) -> Option<Vec<u8>> {
+    let image = image::DynamicImage::ImageRgba8(rgba.clone());
+    if rgba.width() <= THUMBNAIL_SIZE && rgba.height() <= THUMBNAIL_SIZE {
+        return encode_png(&image);
+    }
+    let scaled = image.resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle);
+    encode_png(&scaled)
+}
+
+// images are stored as png, a fraction of the raw rgba the clipboard hands
+// us. rows from before that, or that failed to encode, hold the raw rgba
+fn image_from_column(
+    idx: usize,
+    width: usize,
+    height: usize,
+    bytes: Vec<u8>,
+) -> Result<SerializableImage, rusqlite::Error> {
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        return Ok(SerializableImage {
+            width,
+            height,
+            bytes,
+        });
+    }
+    let rgba = image::load_from_memory_with_format(&bytes, ImageFormat::Png)
+        .map_err(|e| conversion_error(idx, e.to_string()))?
+        .to_rgba8();
+    Ok(SerializableImage {
+        width: rgba.width() as usize,
+        height: rgba.height() as usize,
+        bytes: rgba.into_raw(),
+    })
+}
+
+// both connections wait for the other to finish writing rather than fail
+// with SQLITE_BUSY. with wal, synchronous=NORMAL only syncs at checkpoints:
+// a power cut can lose the last few writes, but never corrupts the db
+fn connect(path: &str) -> Result<Connection, String> {
+    let connection = Connection::open(path).map_err(|e| e.to_string())?;
+    connection
+        .busy_timeout(BUSY_TIMEOUT)
+        .and_then(|_| connection.pragma_update(None, "synchronous", "NORMAL"))
+        .map_err(|e| e.to_string())?;
+    Ok(connection)
+}
+
+// brings the columns of an older db up to date. a db made from the create
+// statements already has them, so a column is only added where it is missing
+fn migrate(connection: &Connection) -> Result<(), String> {
+    let version: usize = connection
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "database is at schema version {}, this slate only knows up to {}, update slate",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+
+    let tx = connection
+        .unchecked_transaction()
+        .map_err(|e| e.to_string())?;
+    for (i, (table, column, kind)) in MIGRATIONS.iter().enumerate().skip(version) {
+        let exists = tx
+            .query_row(
+                "SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2",
+                params![table, column],
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?
+            .is_some();
+        if !exists {
+            tx.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, kind
+            ))
+            .map_err(|e| format!("failed to add {}.{}: {}", table, column, e))?;
+            info!("added column {} to {}", column, table);
+        }
+        tx.pragma_update(None, "user_version", i + 1)
+            .map_err(|e| e.to_string())?;
+    }
+    tx.commit().map_err(|e| e.to_string())
+}
+
+// derives the at rest key from the passphrase, with a salt made on first use.
+// a db that was encrypted before is refused with a wrong or without passphrase
+fn open_storage(
+    connection: &Connection,
+    passphrase: Option<String>,
+) -> Result<Option<Cipher>, String> {
+    let meta = |key: &str| -> Result<Option<Vec<u8>>, String> {
+        connection
+            .query_row(
+                "SELECT value FROM meta WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    };
+    let set_meta = |key: &str, value: &[u8]| -> Result<(), String> {
+        connection
+            .execute(
+                "INSERT INTO meta (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    };
+
+    let check = meta("check")?;
+    let Some(passphrase) = passphrase else {
+        return match check {
+            Some(_) => Err("the database is encrypted, set SLATE_PASSPHRASE".to_string()),
+            None => Ok(None),
+        };
+    };
+
+    let salt = match meta("salt")? {
+        Some(salt) => salt,
+        None => {
+            let salt = rand::random::<[u8; 16]>().to_vec();
+            set_meta("salt", &salt)?;
+            salt
+        }
+    };
+    let cipher = crypto::derive_key(&passphrase, &salt)?;
+
+    // the check value is stored as nonce followed by ciphertext
+    match check {
+        Some(check) if check.len() > 24 => {
+            let (nonce, sealed) = check.split_at(24);
+            match crypto::decrypt_column(&cipher, nonce, CHECK_COLUMN, sealed) {
+                Ok(value) if value == CHECK_VALUE => {}
+                _ => return Err("wrong SLATE_PASSPHRASE for this database".to_string()),
+            }
+        }
+        Some(_) => return Err("invalid passphrase check in the database".to_string()),
+        None => {
+            let mut check = crypto::new_nonce();
+            let sealed = crypto::encrypt_column(&cipher, &check, CHECK_COLUMN, CHECK_VALUE);
+            check.extend(sealed);
+            set_meta("check", &check)?;
+        }
+    }
+    Ok(Some(cipher))
+}
+
+// file names come from the cli and from peers and are joined to the download
+// directory, so anything but a single plain name is refused
+fn check_file_name(name: &str) -> Result<(), String> {
+    let mut components = Path::new(name).components();
+    let plain = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+    if !plain || name.contains(['/', '\\', '\0']) {
+        return Err(format!(
+            "invalid file name {:?}, it must not be a path",
+            name
+        ));
+    }
+    Ok(())
+}
+
+// seconds since the epoch, what expires_at is in. the devices' clocks are
+// assumed to roughly agree
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// e.g. 450.0MB, for messages meant for people
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
     }
 }
 
@@ -496,23 +2082,72 @@ pub enum DBCommand {
         timestamp: Ulid,
         local: bool,
     },
+    // raw leaves the content compressed
     Download {
         download_path: String,
         file_name: String,
+        raw: bool,
     },
     CopyData {
         data: ClipboardEntry,
         timestamp: Ulid,
         local: bool,
+        origin: Option<Origin>,
+        mime: Option<String>,
+        // unix seconds, for entries that delete themselves
+        expires_at: Option<u64>,
     },
-    Paste {
+    // deletes the entry everywhere, origin is None for a local delete
+    Delete {
+        key: Ulid,
+        origin: Option<Origin>,
+    },
+    KeyAt {
         offset: usize,
-        clipboard: ClipboardWrapper,
+    },
+    // the current entry without an offset
+    Paste {
+        offset: Option<usize>,
+        clipboard: SystemClipboard,
+    },
+    // the entry a paste would set, returned rather than set
+    Entry {
+        offset: Option<usize>,
     },
     ListFiles,
-    History,
+    // how many clipboard entries and files are stored
+    Counts,
+    // deletes expired entries and old tombstones, sent every PURGE_INTERVAL
+    PurgeExpired,
+    // a file pulled from a peer, with its content still compressed
+    StoreFile {
+        file: SharedFile,
+        content: Vec<u8>,
+    },
+    FileContent {
+        file_name: String,
+    },
+    // max_history entries unless a limit is given
+    History {
+        limit: Option<usize>,
+        offset: usize,
+        thumbnails: bool,
+    },
+    // every entry and the clock as json, into a file or back to the caller
+    ExportAll {
+        path: Option<String>,
+    },
+    // entries of an export that are missing here, merging its clock
+    ImportAll {
+        path: String,
+        overwrite: bool,
+    },
     Recent {
         length: u64,
+        offset: u64,
+    },
+    Since {
+        clock: Clock,
     },
     InsertSelf {
         host_name: String,
@@ -521,23 +2156,132 @@ pub enum DBCommand {
     SaveClock {
         clock: Clock,
     },
+    PruneClock {
+        keep: Vec<String>,
+    },
+    SaveNeighbors {
+        peers: Vec<PeerInfo>,
+    },
+    LoadNeighbors,
+}
+
+impl DBCommand {
+    // for log lines, without the entry or file content
+    fn name(&self) -> &'static str {
+        match self {
+            DBCommand::Upload { .. } => "upload",
+            DBCommand::Download { .. } => "download",
+            DBCommand::CopyData { .. } => "copy_data",
+            DBCommand::Delete { .. } => "delete",
+            DBCommand::KeyAt { .. } => "key_at",
+            DBCommand::Paste { .. } => "paste",
+            DBCommand::Entry { .. } => "entry",
+            DBCommand::ListFiles => "list_files",
+            DBCommand::Counts => "counts",
+            DBCommand::PurgeExpired => "purge_expired",
+            DBCommand::StoreFile { .. } => "store_file",
+            DBCommand::FileContent { .. } => "file_content",
+            DBCommand::History { .. } => "history",
+            DBCommand::ExportAll { .. } => "export_all",
+            DBCommand::ImportAll { .. } => "import_all",
+            DBCommand::Recent { .. } => "recent",
+            DBCommand::Since { .. } => "since",
+            DBCommand::InsertSelf { .. } => "insert_self",
+            DBCommand::LoadClock => "load_clock",
+            DBCommand::SaveClock { .. } => "save_clock",
+            DBCommand::PruneClock { .. } => "prune_clock",
+            DBCommand::SaveNeighbors { .. } => "save_neighbors",
+            DBCommand::LoadNeighbors => "load_neighbors",
+        }
+    }
+
+    // answered on the read connection
+    fn is_read(&self) -> bool {
+        matches!(
+            self,
+            DBCommand::Download { .. }
+                | DBCommand::KeyAt { .. }
+                | DBCommand::Paste { .. }
+                | DBCommand::Entry { .. }
+                | DBCommand::ListFiles
+                | DBCommand::Counts
+                | DBCommand::FileContent { .. }
+                | DBCommand::History { .. }
+                | DBCommand::ExportAll { .. }
+                | DBCommand::Recent { .. }
+                | DBCommand::Since { .. }
+                | DBCommand::LoadClock
+                | DBCommand::LoadNeighbors
+        )
+    }
 }
 
 #[derive(Debug)]
 pub enum Response {
     Success,
+    // the origin a copied entry was stored with, ours with the next counter
+    // for a local copy
+    Saved {
+        origin: Option<Origin>,
+    },
+    // nothing was stored, the entry has expired or was deleted
+    Skipped,
+    // the origin of the new tombstone, None if it was deleted before
+    Deleted {
+        origin: Option<Origin>,
+    },
+    Key {
+        key: Ulid,
+    },
     Files {
-        names: Vec<String>,
+        files: Vec<FileInfo>,
+    },
+    FileContent {
+        content: Vec<u8>,
+    },
+    // json is only set when the export was not written to a file
+    Exported {
+        entries: usize,
+        files: usize,
+        json: Option<String>,
     },
+    // skipped counts both entries and files that were already here
+    Imported {
+        added: usize,
+        files: usize,
+        skipped: usize,
+    },
+    // more is set when there are older entries past this page
     History {
-        names: Vec<String>,
+        entries: Vec<HistoryEntry>,
+        more: bool,
     },
     Recent {
-        values: Vec<(ClipboardEntry, String)>,
+        values: Vec<ClipboardRecord>,
     },
     Clock {
         data: Clock,
     },
+    Neighbors {
+        peers: Vec<PeerInfo>,
+    },
+    Counts {
+        entries: u64,
+        files: u64,
+    },
+    // a short summary of what was put on the clipboard, and the entry itself
+    Pasted {
+        preview: String,
+        entry: ClipboardEntry,
+    },
+    Entry {
+        entry: ClipboardEntry,
+    },
+    // where a downloaded file was written, and its decompressed size
+    Downloaded {
+        path: String,
+        bytes: u64,
+    },
 }
 
 #[derive(Debug)]
@@ -545,3 +2289,116 @@ pub struct DBMessage {
     pub cmd: DBCommand,
     pub sender: Sender<Result<Response, String>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    // a fresh db in the temp dir, named after the test so tests running at
+    // once do not share one
+    fn database(name: &str) -> Database {
+        let path =
+            std::env::temp_dir().join(format!("slate-test-{}-{}.sqlite", name, std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        let config = Config {
+            db_path: path.to_string_lossy().to_string(),
+            ..Config::default()
+        };
+        Database::new(&config).expect("failed to open test db")
+    }
+
+    fn clock(counters: &[(&str, u64)]) -> Clock {
+        counters
+            .iter()
+            .map(|&(host, counter)| (host.to_string(), counter))
+            .collect()
+    }
+
+    #[test]
+    fn saved_clocks_never_move_back() {
+        let db = database("saved-clocks");
+        db.insert_self("a".to_string()).unwrap();
+        db.sync_clock(&clock(&[("a", 2), ("b", 5)])).unwrap();
+        db.sync_clock(&clock(&[("a", 1), ("b", 3), ("c", 1)]))
+            .unwrap();
+        assert_eq!(
+            db.load_clock().unwrap(),
+            clock(&[("a", 2), ("b", 5), ("c", 1)])
+        );
+        assert_eq!(db.self_host().unwrap().as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn check_file_name_takes_plain_names() {
+        for name in ["notes.txt", "photo 1.png", ".hidden", "a..b"] {
+            assert!(check_file_name(name).is_ok(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn check_file_name_refuses_paths() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "../x",
+            "dir/x",
+            "/etc/passwd",
+            "x/",
+            "dir\\x",
+            "..\\x",
+            "x\0y",
+        ] {
+            assert!(check_file_name(name).is_err(), "{:?}", name);
+        }
+    }
+    // sends a copy of `text` to a db listening on its own thread, as the
+    // control plane does, and returns the origin it was stored with
+    fn copy(
+        db: &mpsc::Sender<DBMessage>,
+        text: &str,
+        local: bool,
+        origin: Option<Origin>,
+    ) -> Option<Origin> {
+        let (sender, reply) = tokio::sync::oneshot::channel();
+        let cmd = DBCommand::CopyData {
+            data: ClipboardEntry::Text(text.to_string()),
+            timestamp: Ulid::new(),
+            local,
+            origin,
+            mime: None,
+            expires_at: None,
+        };
+        db.blocking_send(DBMessage { cmd, sender }).unwrap();
+        match reply.blocking_recv().unwrap() {
+            Ok(Response::Saved { origin }) => origin,
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_local_copies_take_our_counter() {
+        let db = database("local-copies");
+        db.insert_self("a".to_string()).unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        let listener = std::thread::spawn(move || db.listen(rx));
+
+        let peer = Origin {
+            host: "b".to_string(),
+            counter: 4,
+        };
+        let stored = copy(&tx, "from b", false, Some(peer)).unwrap();
+        assert_eq!((stored.host.as_str(), stored.counter), ("b", 4));
+
+        let stored = copy(&tx, "from a", true, None).unwrap();
+        assert_eq!((stored.host.as_str(), stored.counter), ("a", 1));
+        let stored = copy(&tx, "from a again", true, None).unwrap();
+        assert_eq!((stored.host.as_str(), stored.counter), ("a", 2));
+
+        drop(tx);
+        listener.join().unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr},
+    pin::Pin,
+    sync::Arc,
+};
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::control_plane::PeerInfo;
+
+#[cfg(unix)]
+const TAILSCALE_SOCKET: &str = "/var/run/tailscale/tailscaled.sock";
+
+pub type DiscoveryFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, String>> + Send + 'a>>;
+
+// where a node learns its own name, its peers, and the address it serves
+// them on. picked with `discovery` in the config
+pub trait PeerDiscovery: Send + Sync {
+    fn self_host(&self) -> DiscoveryFuture<'_, String>;
+    fn peers(&self) -> DiscoveryFuture<'_, Vec<PeerInfo>>;
+    fn bind_addr(&self) -> DiscoveryFuture<'_, IpAddr>;
+}
+
+pub fn from_config(config: &Config) -> Result<Arc<dyn PeerDiscovery>, String> {
+    match config.discovery.as_str() {
+        "tailscale" => Ok(Arc::new(TailscaleDiscovery::new(config)?)),
+        "static" => Ok(Arc::new(StaticDiscovery::new(config)?)),
+        other => Err(format!(
+            "unknown discovery {}, expected tailscale or static",
+            other
+        )),
+    }
+}
+
+// what we call ourselves when nothing better is known
+fn local_host_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or(std::env::var("HOSTNAME"))
+        .unwrap_or("localhost".to_string())
+}
+
+// peers and our own name from the tailscale local api. peers listed in the
+// config are added to the ones on the tailnet, and are all we have when
+// tailscale is not reachable, e.g. on a machine that is not on it
+pub struct TailscaleDiscovery {
+    host_name: Option<String>,
+    peers: Vec<PeerInfo>,
+}
+
+impl TailscaleDiscovery {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(TailscaleDiscovery {
+            host_name: config.host_name.clone(),
+            peers: parse_static_peers(&config.peers)?,
+        })
+    }
+}
+
+impl PeerDiscovery for TailscaleDiscovery {
+    fn self_host(&self) -> DiscoveryFuture<'_, String> {
+        Box::pin(async {
+            // still useful as a local clipboard history, or with the
+            // configured peers only, when tailscale is down, logged out or
+            // answers with something we do not understand
+            match tailscale_status().await.and_then(|s| self_host_name(&s)) {
+                Ok(name) => Ok(name),
+                Err(e) => {
+                    warn!(
+                        "failed to query tailscale, using configured peers only: {}",
+                        e
+                    );
+                    Ok(self.host_name.clone().unwrap_or_else(local_host_name))
+                }
+            }
+        })
+    }
+
+    fn peers(&self) -> DiscoveryFuture<'_, Vec<PeerInfo>> {
+        Box::pin(async {
+            let mut peers = match tailscale_status().await {
+                Ok(status) => peers_from_status(&status),
+                Err(e) if self.peers.is_empty() => return Err(e),
+                Err(e) => {
+                    debug!("failed to query tailscale, using configured peers: {}", e);
+                    Vec::new()
+                }
+            };
+            // a configured peer that is also on the tailnet keeps what
+            // tailscale says about it, online or not
+            for peer in &self.peers {
+                if !peers.iter().any(|p| p.HostName == peer.HostName) {
+                    peers.push(peer.clone());
+                }
+            }
+            Ok(peers)
+        })
+    }
+
+    // only reachable over the tailnet, never on other networks we are on
+    fn bind_addr(&self) -> DiscoveryFuture<'_, IpAddr> {
+        Box::pin(async {
+            match tailscale_status().await {
+                Ok(status) => Ok(bind_addr_from_status(&status)),
+                Err(e) => {
+                    warn!(
+                        "failed to query tailscale, listening on loopback only: {}",
+                        e
+                    );
+                    Ok(IpAddr::V4(Ipv4Addr::LOCALHOST))
+                }
+            }
+        })
+    }
+}
+
+// a fixed list of peers from the config, for plain lans or wireguard:
+//
+//   discovery = "static"
+//   host_name = "desktop"
+//   peers = ["laptop:192.168.1.20,fd00::2", "phone:fd00::3"]
+//
+// every peer is assumed to be online
+pub struct StaticDiscovery {
+    host_name: String,
+    peers: Vec<PeerInfo>,
+    bind_addr: IpAddr,
+}
+
+impl StaticDiscovery {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let peers = parse_static_peers(&config.peers)?;
+        let bind_addr = config.bind_addr.unwrap_or_else(|| {
+            info!("no bind_addr configured, listening on every interface");
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        });
+
+        Ok(StaticDiscovery {
+            host_name: config.host_name.clone().unwrap_or_else(local_host_name),
+            peers,
+            bind_addr,
+        })
+    }
+}
+
+fn parse_static_peers(peers: &[String]) -> Result<Vec<PeerInfo>, String> {
+    peers.iter().map(|peer| parse_static_peer(peer)).collect()
+}
+
+// "host:ip", split at the first colon since host names cannot hold one. a
+// peer with several addresses lists them all, "host:ip,ip", and they are
+// tried in turn
+fn parse_static_peer(peer: &str) -> Result<PeerInfo, String> {
+    let (host, ips) = peer
+        .split_once(':')
+        .ok_or(format!("peer {} should look like host:ip", peer))?;
+    let ips = ips
+        .split(',')
+        .map(|ip| ip.trim().parse::<IpAddr>().map(|ip| ip.to_string()))
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|_| format!("peer {} has an invalid ip", peer))?;
+    if host.is_empty() {
+        return Err(format!("peer {} has no host name", peer));
+    }
+
+    Ok(PeerInfo {
+        HostName: host.to_string(),
+        TailscaleIPs: ips,
+        Online: true,
+    })
+}
+
+impl PeerDiscovery for StaticDiscovery {
+    fn self_host(&self) -> DiscoveryFuture<'_, String> {
+        Box::pin(async { Ok(self.host_name.clone()) })
+    }
+
+    fn peers(&self) -> DiscoveryFuture<'_, Vec<PeerInfo>> {
+        Box::pin(async { Ok(self.peers.clone()) })
+    }
+
+    fn bind_addr(&self) -> DiscoveryFuture<'_, IpAddr> {
+        Box::pin(async { Ok(self.bind_addr) })
+    }
+}
+
+// raw json from the tailscale local api
+#[cfg(unix)]
+async fn tailscale_status() -> Result<serde_json::Value, String> {
+    use http::{header::HOST, Request};
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Bytes;
+    use hyper_util::client::legacy::Client;
+    use hyperlocal::{UnixClientExt, UnixConnector, Uri};
+
+    let uri = Uri::new(TAILSCALE_SOCKET, "/localapi/v0/status");
+
+    let req = Request::get(uri)
+        .header(HOST, "local-tailscaled.sock")
+        .body(Full::new(Bytes::new()))
+        .map_err(|e| e.to_string())?;
+
+    let client: Client<UnixConnector, Full<Bytes>> = Client::unix();
+
+    let res = client.request(req).await.map_err(|e| e.to_string())?;
+    let body = res.collect().await.map_err(|e| e.to_string())?.to_bytes();
+    serde_json::from_slice(&body).map_err(|e| e.to_string())
+}
+
+// tailscale listens on a named pipe there, so until that is supported
+// slate only works locally
+#[cfg(not(unix))]
+async fn tailscale_status() -> Result<serde_json::Value, String> {
+    Err("the tailscale local api is only supported on unix".to_string())
+}
+
+// picks the address the http server should listen on from a tailscale
+// status: our own ipv4 tailscale address if there is one, otherwise any of
+// our tailscale addresses, otherwise loopback so we never listen publicly
+fn bind_addr_from_status(status: &serde_json::Value) -> IpAddr {
+    let ips: Vec<IpAddr> = status["Self"]["TailscaleIPs"]
+        .as_array()
+        .map(|ips| {
+            ips.iter()
+                .filter_map(|ip| ip.as_str()?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ips.iter()
+        .find(|ip| ip.is_ipv4())
+        .or(ips.first())
+        .copied()
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+// our own host name from a tailscale status. tailscaled answers even when it
+// is logged out, so the backend state is checked first
+fn self_host_name(status: &serde_json::Value) -> Result<String, String> {
+    match status["BackendState"].as_str() {
+        Some("Running") | None => {}
+        Some(state) => {
+            return Err(format!(
+                "tailscale is {}, is it running and logged in?",
+                state
+            ))
+        }
+    }
+    status["Self"]["HostName"]
+        .as_str()
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .ok_or("tailscale reported no host name, is it running and logged in?".to_string())
+}
+
+// the peers in a tailscale status. entries that do not look like a peer are
+// skipped, rather than failing the whole list
+fn peers_from_status(status: &serde_json::Value) -> Vec<PeerInfo> {
+    let Some(peers) = status["Peer"].as_object() else {
+        return Vec::new();
+    };
+    peers
+        .iter()
+        .filter_map(
+            |(key, peer)| match serde_json::from_value::<PeerInfo>(peer.clone()) {
+                Ok(peer) => Some(peer),
+                Err(e) => {
+                    warn!("skipping malformed peer {}: {}", key, e);
+                    None
+                }
+            },
+        )
+        .collect()
+}
@@ -1,59 +1,227 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use axum::{
-    response::IntoResponse,
+    extract::{Path, Query, Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Extension, Json, Router,
 };
-use http::StatusCode;
+use axum_server::tls_rustls::RustlsConfig;
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc::Sender, oneshot};
+use tracing::{debug, error, info, trace, warn};
+use ulid::Ulid;
 
 use crate::{
-    control_plane::{ControlMessage, Gossip, PeerInfo},
-    db::{ClipboardEntry, Clock, DBMessage},
+    config::Config,
+    control_plane::{ControlMessage, Gossip, GossipMeta, PeerInfo, Source},
+    crypto::{Cipher, Records},
+    db::{Clock, DBMessage, SharedFile},
+    metrics::Metrics,
 };
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    // ca that signed our peers' certificates, for self signed setups
+    pub ca_path: Option<String>,
+}
+
+// compares without bailing out early, so response timing does not leak the token
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn require_token(
+    State(token): State<Option<String>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(token) = token else {
+        return Ok(next.run(req).await);
+    };
+
+    let given = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match given {
+        Some(given) if token_matches(&token, given) => Ok(next.run(req).await),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
 async fn health_check() -> &'static str {
     "hai"
 }
 
-async fn clock(Extension(tx): Extension<Sender<ControlMessage>>) -> Json<Clock> {
+// read straight from the db rather than through the control plane, which may
+// be busy syncing with the very peer asking
+async fn load_clock(tx: &Sender<DBMessage>) -> Result<Clock, String> {
     let (x, y) = oneshot::channel();
-    tx.send(ControlMessage {
-        cmd: crate::control_plane::ControlCommand::GetClock,
+    let msg = DBMessage {
+        cmd: crate::db::DBCommand::LoadClock,
         sender: x,
-    })
-    .await
-    .expect("failed to send control message");
+    };
+    tx.send(msg).await.expect("failed to send db message");
 
-    let response = y.await.expect("failed to get response");
-    if let Ok(crate::control_plane::Response::Clock { data }) = response {
+    match y.await.expect("failed to get response")? {
+        crate::db::Response::Clock { data } => Ok(data),
+        _ => Err("unexpected response to LoadClock".to_string()),
+    }
+}
+
+async fn clock(Extension(tx): Extension<Sender<DBMessage>>) -> Json<Clock> {
+    if let Ok(data) = load_clock(&tx).await {
         Json(data)
     } else {
-        eprintln!("failed to get clock?");
+        error!("failed to get clock?");
         let data = HashMap::new();
         Json(data)
     }
 }
 
+#[derive(Deserialize)]
+#[serde(default)]
+struct RecentParams {
+    limit: u64,
+    offset: u64,
+}
+
+impl Default for RecentParams {
+    fn default() -> Self {
+        RecentParams {
+            limit: 100,
+            offset: 0,
+        }
+    }
+}
+
+// newest entries first, a page of them with ?limit=&offset=
 async fn recent_clipboard(
     Extension(tx): Extension<Sender<DBMessage>>,
-) -> Json<Vec<(ClipboardEntry, String)>> {
+    Extension(cipher): Extension<Option<Cipher>>,
+    Query(params): Query<RecentParams>,
+) -> Json<Records> {
+    let (x, y) = oneshot::channel();
+    let msg = DBMessage {
+        cmd: crate::db::DBCommand::Recent {
+            length: params.limit,
+            offset: params.offset,
+        },
+        sender: x,
+    };
+    tx.send(msg).await.expect("failed to send db message");
+
+    let resp = y.await.expect("failed to read response");
+    let values = match resp {
+        Ok(crate::db::Response::Recent { values }) => values,
+        _ => Vec::new(),
+    };
+    Json(Records::new(values, cipher.as_ref(), b"recent_clipboard"))
+}
+
+#[derive(Deserialize)]
+struct SinceParams {
+    // json encoded clock of the requester
+    clock: String,
+}
+
+async fn since(
+    Extension(tx): Extension<Sender<DBMessage>>,
+    Extension(cipher): Extension<Option<Cipher>>,
+    Query(params): Query<SinceParams>,
+) -> Result<Json<Records>, StatusCode> {
+    let clock: Clock = serde_json::from_str(&params.clock).map_err(|_| StatusCode::BAD_REQUEST)?;
+
     let (x, y) = oneshot::channel();
     let msg = DBMessage {
-        cmd: crate::db::DBCommand::Recent { length: 100 },
+        cmd: crate::db::DBCommand::Since { clock },
         sender: x,
     };
     tx.send(msg).await.expect("failed to send db message");
 
     let resp = y.await.expect("failed to read response");
     if let Ok(crate::db::Response::Recent { values }) = resp {
-        Json(values)
+        let asked = format!("since {}", params.clock);
+        Ok(Json(Records::new(
+            values,
+            cipher.as_ref(),
+            asked.as_bytes(),
+        )))
+    } else {
+        Err(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+// files we can hand out, ones stored before hashes were recorded are left out
+async fn files(Extension(tx): Extension<Sender<DBMessage>>) -> Json<Vec<SharedFile>> {
+    let (x, y) = oneshot::channel();
+    let msg = DBMessage {
+        cmd: crate::db::DBCommand::ListFiles,
+        sender: x,
+    };
+    tx.send(msg).await.expect("failed to send db message");
+
+    let resp = y.await.expect("failed to read response");
+    if let Ok(crate::db::Response::Files { files }) = resp {
+        let shared = files
+            .into_iter()
+            .filter_map(|f| {
+                Some(SharedFile {
+                    hash: f.hash?,
+                    size_bytes: f.size_bytes?,
+                    uploaded_at: f.uploaded_at.map(|u| u.to_string()),
+                    source_host: f.source_host,
+                    name: f.name,
+                })
+            })
+            .collect();
+        Json(shared)
     } else {
         Json(Vec::new())
     }
 }
 
+// the zstd compressed content, the peer keeps it as it is
+async fn download(
+    Extension(tx): Extension<Sender<DBMessage>>,
+    Path(name): Path<String>,
+) -> Result<Vec<u8>, StatusCode> {
+    let (x, y) = oneshot::channel();
+    let msg = DBMessage {
+        cmd: crate::db::DBCommand::FileContent { file_name: name },
+        sender: x,
+    };
+    tx.send(msg).await.expect("failed to send db message");
+
+    match y.await.expect("failed to read response") {
+        Ok(crate::db::Response::FileContent { content }) => Ok(content),
+        Ok(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(e) => {
+            debug!("not serving file: {}", e);
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
 async fn neighbors(Extension(tx): Extension<Sender<ControlMessage>>) -> Json<Vec<PeerInfo>> {
     let (x, y) = oneshot::channel();
     let msg = ControlMessage {
@@ -63,49 +231,131 @@ async fn neighbors(Extension(tx): Extension<Sender<ControlMessage>>) -> Json<Vec
     tx.send(msg).await.expect("failed to send db message");
 
     let resp = y.await.expect("failed to read response");
-    if let Ok(crate::control_plane::Response::Neighbors { info }) = resp {
+    if let Ok(crate::control_plane::Response::Neighbors { info, .. }) = resp {
         Json(info)
     } else {
         Json(Vec::new())
     }
 }
 
+#[derive(Serialize)]
+struct Stats {
+    entries: u64,
+    files: u64,
+    clock: Clock,
+    neighbors: usize,
+    uptime_secs: u64,
+}
+
+// for dashboards watching several devices at once
+async fn stats(
+    Extension(dtx): Extension<Sender<DBMessage>>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+) -> Result<Json<Stats>, StatusCode> {
+    load_stats(&dtx, &metrics).await.map(Json)
+}
+
+async fn scrape(
+    Extension(dtx): Extension<Sender<DBMessage>>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let stats = load_stats(&dtx, &metrics).await?;
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(stats.entries, stats.files),
+    ))
+}
+
+// straight from the db and the shared metrics, like /clock, so a scrape is
+// not held up by an anti entropy round on the control plane
+async fn load_stats(dtx: &Sender<DBMessage>, metrics: &Metrics) -> Result<Stats, StatusCode> {
+    let (x, y) = oneshot::channel();
+    let msg = DBMessage {
+        cmd: crate::db::DBCommand::Counts,
+        sender: x,
+    };
+    dtx.send(msg).await.expect("failed to send db message");
+    let (entries, files) = match y.await.expect("failed to read response") {
+        Ok(crate::db::Response::Counts { entries, files }) => (entries, files),
+        _ => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let clock = load_clock(dtx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Stats {
+        entries,
+        files,
+        clock,
+        neighbors: metrics.neighbors() as usize,
+        uptime_secs: metrics.uptime().as_secs(),
+    })
+}
+
 async fn gossip(
     Extension(tx): Extension<Sender<ControlMessage>>,
+    Extension(dtx): Extension<Sender<DBMessage>>,
+    Extension(cipher): Extension<Option<Cipher>>,
+    Extension(metrics): Extension<Arc<Metrics>>,
     Json(payload): Json<Gossip>,
 ) -> impl IntoResponse {
-    println!("got request");
-    let Gossip { clock, entry, ttl } = payload;
-    let cur_clock = {
-        let (x, y) = oneshot::channel();
-        let msg = ControlMessage {
-            cmd: crate::control_plane::ControlCommand::GetClock,
-            sender: x,
-        };
-        tx.send(msg).await.expect("failed to send msg");
-        y.await
-            .expect("failed to recieve msg")
-            .expect("could not get clock")
+    trace!("got request");
+    let Gossip { entry, meta } = payload;
+    // opened first, the rest can only be trusted once the entry was sealed with it
+    let entry = match entry.open(cipher.as_ref(), &meta.sealed_bytes()) {
+        Ok(entry) => entry,
+        Err(e) => {
+            warn!("rejecting gossip: {}", e);
+            return StatusCode::UNAUTHORIZED;
+        }
+    };
+    let GossipMeta {
+        clock,
+        ttl,
+        origin,
+        mime,
+        key,
+        expires_at,
+        deleted,
+    } = meta;
+    let key = match key.map(|k| Ulid::from_string(&k)).transpose() {
+        Ok(key) => key,
+        Err(_) => return StatusCode::BAD_REQUEST,
     };
-    if let crate::control_plane::Response::Clock { data } = cur_clock {
+    // a tombstone is nothing without the entry it is for and who deleted it
+    if deleted && (key.is_none() || origin.is_none()) {
+        return StatusCode::BAD_REQUEST;
+    }
+    Metrics::add(&metrics.gossip_received, 1);
+    if let Ok(data) = load_clock(&dtx).await {
         let mut res = StatusCode::OK;
-        if crate::control_plane::is_outdated(&data, &clock) && ttl > 0 {
-            println!("inserting value!");
-            let (x, y) = oneshot::channel();
-            let msg = ControlMessage {
-                cmd: crate::control_plane::ControlCommand::Transmit {
+        if crate::control_plane::is_outdated(&data, &clock) {
+            debug!("inserting value!");
+            let cmd = match key {
+                Some(key) if deleted => crate::control_plane::ControlCommand::Delete {
+                    key,
+                    ttl: Some(ttl),
+                    source: Source::Peer,
+                    origin,
+                },
+                _ => crate::control_plane::ControlCommand::Transmit {
+                    source: Source::Peer,
                     data: entry,
-                    ttl: Some(ttl - 1),
-                    clock: Some(clock)
+                    ttl: Some(ttl),
+                    origin,
+                    mime,
+                    key,
+                    expires_at,
                 },
-                sender: x,
             };
+            let (x, y) = oneshot::channel();
+            let msg = ControlMessage { cmd, sender: x };
             tx.send(msg).await.expect("failed to send msg");
             let resp = y.await.expect("failed to send msg");
             res = match resp {
                 Ok(crate::control_plane::Response::OK) => StatusCode::OK,
                 Err(e) => {
-                    eprintln!("{}", e);
+                    error!("{}", e);
                     StatusCode::INTERNAL_SERVER_ERROR
                 }
                 _ => {
@@ -119,20 +369,59 @@ async fn gossip(
     }
 }
 
-pub async fn run_http_server(dtx: Sender<DBMessage>, ctx: Sender<ControlMessage>) {
+pub async fn run_http_server(
+    dtx: Sender<DBMessage>,
+    ctx: Sender<ControlMessage>,
+    ip: IpAddr,
+    config: Config,
+    cipher: Option<Cipher>,
+    metrics: Arc<Metrics>,
+) {
+    let Config {
+        port, token, tls, ..
+    } = config;
+    if token.is_none() {
+        warn!("no auth token configured, http api is open to the tailnet");
+    }
+
     let app = Router::new()
         //.nest()
-        .route("/health", get(health_check))
         .route("/clock", get(clock))
         .route("/recent_clipboard", get(recent_clipboard))
+        .route("/since", get(since))
         .route("/neighbors", get(neighbors))
+        .route("/files", get(files))
+        .route("/download/{name}", get(download))
+        .route("/stats", get(stats))
+        .route("/metrics", get(scrape))
         .route("/gossip", post(gossip))
+        .route_layer(middleware::from_fn_with_state(token, require_token))
+        // left open so peers can probe us without credentials
+        .route("/health", get(health_check))
         .layer(Extension(dtx))
+        .layer(Extension(cipher))
+        .layer(Extension(metrics))
         .layer(Extension(ctx));
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    println!("running on localhost:3000");
-    axum::serve(listener, app)
-        .await
-        .expect("failed to start server");
+    match tls {
+        Some(tls) => {
+            // several of our dependencies enable rustls, so pick the provider explicitly
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            let config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                .await
+                .expect("failed to load tls certificate");
+            info!("running on https://{}:{}", ip, port);
+            axum_server::bind_rustls(SocketAddr::new(ip, port), config)
+                .serve(app.into_make_service())
+                .await
+                .expect("failed to start server");
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind((ip, port)).await.unwrap();
+            info!("running on {}:{}", ip, port);
+            axum::serve(listener, app)
+                .await
+                .expect("failed to start server");
+        }
+    }
 }
@@ -0,0 +1,204 @@
+use std::future::Future;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::db::ClipboardEntry;
+
+// what the cli asks the daemon, one frame of json per connection (see
+// protocol). names, paths and clipboard text travel as json strings, so
+// spaces and newlines in them are not a problem
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Upload {
+        file_name: String,
+        file_path: String,
+    },
+    Download {
+        file_name: String,
+        path: String,
+        // the zstd compressed content, as it is stored
+        #[serde(default)]
+        raw: bool,
+    },
+    Export {
+        path: Option<String>,
+    },
+    Import {
+        path: String,
+        // replace files of the same name that differ
+        #[serde(default)]
+        overwrite: bool,
+    },
+    Files,
+    Copy {
+        // how long the entry lives before every node deletes it
+        #[serde(default)]
+        ttl_secs: Option<u64>,
+        // what was piped into the cli, instead of the system clipboard
+        #[serde(default)]
+        entry: Option<ClipboardEntry>,
+        // the primary selection instead of the clipboard
+        #[serde(default)]
+        primary: bool,
+    },
+    Paste {
+        offset: Option<usize>,
+        #[serde(default)]
+        primary: bool,
+    },
+    // the entry itself in the reply, instead of setting the clipboard
+    PasteRaw {
+        offset: Option<usize>,
+    },
+    Delete {
+        offset: Option<usize>,
+    },
+    History {
+        limit: Option<usize>,
+        offset: usize,
+        #[serde(default)]
+        thumbnails: bool,
+    },
+    Status,
+    Clock,
+    Peers,
+    Sync {
+        // compare clocks only, without pulling anything
+        #[serde(default)]
+        dry_run: bool,
+    },
+    Watch {
+        enabled: bool,
+    },
+}
+
+impl Request {
+    // the command as it is sent, for log lines
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Upload { .. } => "upload",
+            Request::Download { .. } => "download",
+            Request::Export { .. } => "export",
+            Request::Import { .. } => "import",
+            Request::Files => "files",
+            Request::Copy { .. } => "copy",
+            Request::Paste { .. } => "paste",
+            Request::PasteRaw { .. } => "paste_raw",
+            Request::Delete { .. } => "delete",
+            Request::History { .. } => "history",
+            Request::Status => "status",
+            Request::Clock => "clock",
+            Request::Peers => "peers",
+            Request::Sync { .. } => "sync",
+            Request::Watch { .. } => "watch",
+        }
+    }
+}
+
+// the daemon's answer, one frame of json before it closes the connection
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Reply {
+    // how a command went
+    Message {
+        text: String,
+    },
+    // something to show, as text for people and as json for `--json`
+    Output {
+        text: String,
+        data: serde_json::Value,
+    },
+    // the command failed
+    Error {
+        text: String,
+    },
+}
+
+// how the cli reaches the daemon: a unix socket, or a named pipe on windows.
+// `socket_path` in the config is the pipe name there (e.g. \\.\pipe\slate)
+pub trait Listener: Sized {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn bind(path: &str) -> io::Result<Self>;
+    fn accept(&mut self) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+#[cfg(unix)]
+pub use unix::{cleanup, connect, UnixSocket as DaemonListener};
+#[cfg(windows)]
+pub use windows::{cleanup, connect, NamedPipe as DaemonListener};
+
+#[cfg(unix)]
+mod unix {
+    use std::{fs, io};
+    use tokio::net::{UnixListener, UnixStream};
+
+    pub struct UnixSocket(UnixListener);
+
+    impl super::Listener for UnixSocket {
+        type Stream = UnixStream;
+
+        fn bind(path: &str) -> io::Result<Self> {
+            // left behind if the daemon was not stopped cleanly
+            if fs::metadata(path).is_ok() {
+                fs::remove_file(path)?;
+            }
+            UnixListener::bind(path).map(UnixSocket)
+        }
+
+        async fn accept(&mut self) -> io::Result<UnixStream> {
+            self.0.accept().await.map(|(stream, _)| stream)
+        }
+    }
+
+    pub fn connect(path: &str) -> io::Result<std::os::unix::net::UnixStream> {
+        std::os::unix::net::UnixStream::connect(path)
+    }
+
+    pub fn cleanup(path: &str) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::{fs, io};
+    use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+
+    // a named pipe serves one client per instance, so a fresh instance is
+    // created every time one gets connected
+    pub struct NamedPipe {
+        path: String,
+        server: NamedPipeServer,
+    }
+
+    impl super::Listener for NamedPipe {
+        type Stream = NamedPipeServer;
+
+        fn bind(path: &str) -> io::Result<Self> {
+            let server = ServerOptions::new()
+                .first_pipe_instance(true)
+                .create(path)?;
+            Ok(NamedPipe {
+                path: path.to_string(),
+                server,
+            })
+        }
+
+        async fn accept(&mut self) -> io::Result<NamedPipeServer> {
+            self.server.connect().await?;
+            let next = ServerOptions::new().create(&self.path)?;
+            Ok(std::mem::replace(&mut self.server, next))
+        }
+    }
+
+    pub fn connect(path: &str) -> io::Result<fs::File> {
+        fs::OpenOptions::new().read(true).write(true).open(path)
+    }
+
+    // pipes disappear with the process that created them
+    pub fn cleanup(_path: &str) {}
+}
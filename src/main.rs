@@ -1,40 +1,96 @@
+mod clipboard;
+mod config;
 mod control_plane;
+mod crypto;
 mod daemon;
 mod db;
+mod discovery;
 mod http_server;
+mod ipc;
+mod metrics;
+mod platform;
+mod protocol;
 
-use std::io::{BufRead, BufReader, Write};
-use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::Path;
 
 use daemon::start_daemon;
 use daemon::stop_daemon;
-use daemon::SOCKET_PATH;
+use db::{ClipboardEntry, SerializableImage};
 
-use clap::{Parser, Subcommand};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ipc::{Reply, Request};
+
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell;
+use tracing::{debug, error};
+use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
 #[command(name = "slate", about = "manage files and clipboards across devices")]
 struct SlateCLI {
     #[command(subcommand)]
     command: SlateCommand,
+    /// print diagnostics, twice for even more
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// print json instead of text, for scripts
+    #[arg(long, global = true)]
+    json: bool,
+    /// talk to (or start) the daemon on this socket, for a second instance
+    #[arg(long, global = true, value_hint = ValueHint::AnyPath)]
+    socket: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 enum SlateCommand {
     /// copy data to the clipboard manager
-    Copy,
+    Copy {
+        /// delete the entry everywhere after this long, e.g. 30s, 5m, 1h or 2d
+        #[arg(long, value_parser = parse_duration)]
+        ttl: Option<u64>,
+        /// copy text or a png piped in, e.g. `cat notes.txt | slate copy --stdin`
+        #[arg(long)]
+        stdin: bool,
+        /// copy the primary selection (what is selected, pasted with a middle click) instead
+        #[arg(long, conflicts_with = "stdin")]
+        primary: bool,
+    },
     /// paste data from the clipboard manager
-    Paste { offset: Option<usize> },
+    Paste {
+        offset: Option<usize>,
+        /// print the entry instead, text as it is and images as png
+        #[arg(long)]
+        stdout: bool,
+        /// set the primary selection instead of the clipboard
+        #[arg(long, conflicts_with = "stdout")]
+        primary: bool,
+    },
+    /// delete a clipboard entry, on every device as they sync
+    Delete {
+        /// how many entries back, the current one by default
+        offset: Option<usize>,
+    },
     /// upload a file
     Upload {
         /// file name for the upload
         filename: String,
         /// path to the desired upload file
+        #[arg(value_hint = ValueHint::FilePath)]
         filepath: String,
     },
     /// show clipboard history
-    History,
+    History {
+        /// how many entries to show, max_history from the config by default
+        #[arg(long)]
+        limit: Option<usize>,
+        /// how many of the newest entries to skip
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// include png thumbnails of images, base64 in the --json output
+        #[arg(long)]
+        thumbnails: bool,
+    },
     /// list saved files
     Files,
     /// download file specified by name
@@ -42,7 +98,26 @@ enum SlateCommand {
         /// name of the file to download
         filename: String,
         /// where you want the file downloaded
+        #[arg(value_hint = ValueHint::AnyPath)]
         filepath: Option<String>,
+        /// keep it zstd compressed as it is stored, named <name>.zst in a directory
+        #[arg(long)]
+        raw: bool,
+    },
+    /// write the clipboard history and files as json, to a file or stdout
+    Export {
+        /// file to write, compressed if it ends in .zst, stdout (without files) if left out
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: Option<String>,
+    },
+    /// add the entries and files of an export that are missing here
+    Import {
+        /// file written by `slate export`
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: String,
+        /// replace files here that have the same name as one in the export
+        #[arg(long)]
+        overwrite: bool,
     },
     /// start the daemon service
     Start,
@@ -50,116 +125,389 @@ enum SlateCommand {
     Stop,
     /// restart the daemon service
     Restart,
+    /// check whether the daemon is running
+    Status,
+    /// print the end of the daemon's log
+    Logs {
+        /// how many lines to print
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+        /// keep printing what the daemon logs, until interrupted
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// list peers, their addresses and whether they are online
+    #[command(visible_alias = "neighbors")]
+    Peers,
+    /// pull missing entries from peers right now
+    Sync {
+        /// only show which peers are ahead and by about how many entries
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// show the vector clock, the last counter seen of every host
+    Clock,
+    /// automatically save clipboard changes
+    Watch { state: Toggle },
+    /// inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// print a completion script, e.g. `slate completions bash > /etc/bash_completion.d/slate`
+    #[command(hide = true)]
+    Completions { shell: Shell },
+    /// run the daemon in the foreground, used to start it where we cannot fork
+    #[command(hide = true)]
+    Daemon,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// print the effective configuration
+    Show,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum Toggle {
+    On,
+    Off,
+}
+
+// log lines go to stderr, which is the log file once the daemon is started.
+// SLATE_LOG (or RUST_LOG) takes the usual filter syntax, e.g. `debug` or
+// `slate=trace`, and -v / -vv override it with our own debug / trace lines
+fn init_logging(verbose: u8) {
+    let filter = match verbose {
+        0 => EnvFilter::try_from_env("SLATE_LOG")
+            .or_else(|_| EnvFilter::try_from_default_env())
+            .unwrap_or_else(|_| EnvFilter::new("info")),
+        1 => EnvFilter::new("slate=debug"),
+        _ => EnvFilter::new("slate=trace"),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .init();
 }
 
 fn main() {
     let cli = SlateCLI::parse();
-    println!("{:?}", cli);
+    init_logging(cli.verbose);
+    debug!("{:?}", cli);
+    // through the env, so a daemon started from here gets it too
+    if let Some(socket) = &cli.socket {
+        std::env::set_var("SLATE_SOCKET", socket);
+    }
+    let config = config::Config::load();
+    let socket = config.socket_path.as_str();
+    let json = cli.json;
+    let send = |request: Request| send_command(socket, request, json);
+    let say = |message: &str| print_message(message, json);
 
     use SlateCommand::*;
     match cli.command {
         Start => {
-            match start_daemon() {
-                Err(e) => {
-                    eprintln!("{}", e)
-                }
-                Ok(_) => {
-                    println!("daemon started!")
-                }
+            match start_daemon(config.clone()) {
+                Err(e) => fail(&e, EXIT_FAILED, json),
+                Ok(_) => say("daemon started!"),
             };
         }
         Stop => {
-            match stop_daemon() {
-                Ok(_) => println!("daemon stopped"),
-                Err(_) => println!("daemon was not running"),
+            match stop_daemon(&config) {
+                Ok(true) => say("daemon stopped"),
+                Ok(false) => fail("daemon was not running", EXIT_NOT_RUNNING, json),
+                Err(e) => fail(&e, EXIT_FAILED, json),
             };
         }
         Restart => {
-            let _ = stop_daemon();
-            match start_daemon() {
-                Ok(_) => println!("daemon restarted"),
-                Err(_) => println!("unable to restart daemon"),
+            if let Err(e) = stop_daemon(&config) {
+                fail(&e, EXIT_FAILED, json);
+            }
+            match start_daemon(config.clone()) {
+                Ok(_) => say("daemon restarted"),
+                Err(e) => fail(
+                    &format!("unable to restart daemon: {}", e),
+                    EXIT_FAILED,
+                    json,
+                ),
             };
         }
-        Copy => {
-            send_command("copy");
+        Status => {
+            send(Request::Status);
         }
-        Paste { offset } => {
-            let offset = {
-                match offset {
-                    Some(x) => x,
-                    None => 0,
-                }
-            };
-            send_command(&format!("paste {}", offset));
-        }
-        History => {
-            send_command("history");
+        Logs { lines, follow } => {
+            if let Err(e) = daemon::show_log(&config, lines, follow) {
+                fail(&e, EXIT_FAILED, json);
+            }
         }
+        Copy {
+            ttl,
+            stdin: false,
+            primary,
+        } => send(Request::Copy {
+            ttl_secs: ttl,
+            entry: None,
+            primary,
+        }),
+        Copy {
+            ttl, stdin: true, ..
+        } => match read_stdin() {
+            Ok(entry) => send(Request::Copy {
+                ttl_secs: ttl,
+                entry: Some(entry),
+                primary: false,
+            }),
+            Err(e) => fail(&e, EXIT_FAILED, json),
+        },
+        Watch { state } => send(Request::Watch {
+            enabled: matches!(state, Toggle::On),
+        }),
+        Paste {
+            offset,
+            stdout: false,
+            primary,
+        } => send(Request::Paste { offset, primary }),
+        Paste {
+            offset,
+            stdout: true,
+            ..
+        } => paste_to_stdout(socket, offset, json),
+        Delete { offset } => send(Request::Delete { offset }),
+        History {
+            limit,
+            offset,
+            thumbnails,
+        } => send(Request::History {
+            limit,
+            offset,
+            thumbnails,
+        }),
         Files => {
-            send_command("files");
+            send(Request::Files);
         }
-        Upload { filename, filepath } => {
-            let pwd = std::env::current_dir().unwrap();
-            let path = PathBuf::from(filepath);
-
-            let final_path = pwd.join(path);
-            let filepath = final_path.to_string_lossy();
-
-            send_command(&format!("upload {} {}", filename, filepath));
+        Peers => {
+            send(Request::Peers);
         }
-        Download { filename, filepath } => {
-            let pwd = std::env::current_dir().unwrap();
-            let filepath = {
-                if let Some(filepath) = filepath {
-                    let path = PathBuf::from(filepath);
-                    pwd.join(path)
-                } else {
-                    pwd
-                }
-            };
-            send_command(&format!(
-                "download {} {}",
-                filename,
-                filepath.to_string_lossy()
-            ));
+        Sync { dry_run } => {
+            send(Request::Sync { dry_run });
         }
+        Clock => {
+            send(Request::Clock);
+        }
+        Daemon => {
+            if let Err(e) = daemon::run_in_foreground(config) {
+                error!("{}", e);
+                std::process::exit(EXIT_FAILED);
+            }
+        }
+        Completions { shell } => {
+            clap_complete::generate(
+                shell,
+                &mut SlateCLI::command(),
+                "slate",
+                &mut std::io::stdout(),
+            );
+        }
+        Config { action } => match action {
+            ConfigAction::Show if json => println!("{}", config.show_json()),
+            ConfigAction::Show => print!("{}", config.show()),
+        },
+        Upload { filename, filepath } => match absolute_path(&filepath) {
+            Ok(file_path) => send(Request::Upload {
+                file_name: filename,
+                file_path,
+            }),
+            Err(e) => fail(&e, EXIT_FAILED, json),
+        },
+        Export { path } => match path.map(absolute_path).transpose() {
+            Ok(path) => send(Request::Export { path }),
+            Err(e) => fail(&e, EXIT_FAILED, json),
+        },
+        Import { path, overwrite } => match absolute_path(&path) {
+            Ok(path) => send(Request::Import { path, overwrite }),
+            Err(e) => fail(&e, EXIT_FAILED, json),
+        },
+        Download {
+            filename,
+            filepath,
+            raw,
+        } => match absolute_path(filepath.as_deref().unwrap_or("")) {
+            Ok(path) => send(Request::Download {
+                file_name: filename,
+                path,
+                raw,
+            }),
+            Err(e) => fail(&e, EXIT_FAILED, json),
+        },
     }
 }
 
-fn send_command(command: &str) {
-    match UnixStream::connect(SOCKET_PATH) {
-        Ok(mut stream) => {
-            let write = writeln!(stream, "{}", command);
-            if write.is_err() {
-                eprintln!("failed to send msg");
-                return;
-            }
+// the daemon may run elsewhere than the cli's working directory, so paths are
+// sent absolute. they go as json strings, and a lossy conversion of a path
+// that is not unicode would point the daemon at some other file
+fn absolute_path(path: impl AsRef<Path>) -> Result<String, String> {
+    let cwd = std::env::current_dir().map_err(|e| format!("no working directory: {}", e))?;
+    let path = cwd.join(path);
+    path.to_str()
+        .map(|p| p.to_string())
+        .ok_or_else(|| format!("{} is not a unicode path", path.display()))
+}
 
-            let mut response = String::new();
-            let read = BufReader::new(stream).read_line(&mut response);
-            if read.is_err() {
-                eprintln!("failed to read response");
-                return;
-            }
-            match response {
-                r if r.starts_with("slate_files ") => {
-                    let response = r.strip_prefix("slate_files ").unwrap();
-                    let formatted_files = response
-                        .split(" ")
-                        .map(|s| s.to_string())
-                        .collect::<Vec<String>>();
-                    println!(
-                        "response ({} files): {}",
-                        formatted_files.len(),
-                        formatted_files.join("\n")
-                    );
-                }
-                _ => println!("response: {}", response.trim()),
-            }
+// stdin as a clipboard entry: text if it is utf-8, otherwise it has to be a
+// png, which is stored like a copied image
+fn read_stdin() -> Result<ClipboardEntry, String> {
+    let mut input = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut input)
+        .map_err(|e| format!("failed to read stdin: {}", e))?;
+    let input = match String::from_utf8(input) {
+        Ok(text) => return Ok(ClipboardEntry::Text(text)),
+        Err(e) => e.into_bytes(),
+    };
+    SerializableImage::decode(&input)
+        .map(ClipboardEntry::Image)
+        .map_err(|_| "stdin is neither utf-8 text nor a png or jpeg image".to_string())
+}
+
+// seconds in a human duration like 90s, 5m, 1h or 2d. a bare number is seconds
+fn parse_duration(duration: &str) -> Result<u64, String> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("{} is not a duration like 5m or 1h", duration))?;
+    let unit = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit {}, expected s, m, h or d", unit)),
+    };
+    match amount.checked_mul(unit) {
+        Some(0) | None => Err(format!("{} is not a usable duration", duration)),
+        Some(secs) => Ok(secs),
+    }
+}
+
+// clap already exits with 2 when the arguments are wrong
+const EXIT_FAILED: i32 = 1;
+// like systemctl status for a service that is not running
+const EXIT_NOT_RUNNING: i32 = 3;
+
+// says what went wrong, on stderr or as json, and exits with code
+fn fail(message: &str, code: i32, json: bool) -> ! {
+    if json {
+        println!("{}", serde_json::json!({ "error": message }));
+    } else {
+        eprintln!("{}", message);
+    }
+    std::process::exit(code)
+}
+
+// a message of our own, as text or as the json the daemon would answer with
+fn print_message(message: &str, json: bool) {
+    if json {
+        println!("{}", serde_json::json!({ "message": message }));
+    } else {
+        println!("{}", message);
+    }
+}
+
+// sends a request and waits for the daemon's reply. errors come with the
+// code to exit with
+fn ask(socket: &str, request: Request) -> Result<Reply, (i32, String)> {
+    let failed = |e: &str| (EXIT_FAILED, e.to_string());
+    let mut stream = ipc::connect(socket)
+        .map_err(|_| (EXIT_NOT_RUNNING, "daemon is not running".to_string()))?;
+    debug!("sending {:?}", request);
+    let request = serde_json::to_vec(&request).expect("failed to serialize request");
+    stream
+        .write_all(&protocol::encode(protocol::REQUEST, &request))
+        .map_err(|_| failed("failed to send msg"))?;
+
+    // the daemon closes the connection once it has answered, nothing at all
+    // means it went away before that
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .map_err(|_| failed("failed to read response"))?;
+    if response.is_empty() {
+        return Err(failed("the daemon did not answer"));
+    }
+    let frame = match protocol::decode(&response) {
+        Ok(Some((frame, _))) if frame.kind == protocol::REPLY => frame,
+        Ok(_) => return Err(failed("the daemon's answer was cut short")),
+        Err(e) => return Err(failed(&format!("failed to read response: {}", e))),
+    };
+    debug!("received {:?}", String::from_utf8_lossy(&frame.payload));
+    serde_json::from_slice(&frame.payload)
+        .map_err(|e| failed(&format!("failed to read response: {}", e)))
+}
+
+fn send_command(socket: &str, request: Request, json: bool) {
+    match ask(socket, request) {
+        Ok(Reply::Message { text }) if json => print_message(&text, json),
+        Ok(Reply::Message { text }) => println!("response: {}", text),
+        Ok(Reply::Output { data, .. }) if json => println!("{}", data),
+        Ok(Reply::Output { text, .. }) => print!("{}", text),
+        Ok(Reply::Error { text }) => fail(&text, EXIT_FAILED, json),
+        Err((code, e)) => fail(&e, code, json),
+    }
+}
+
+// writes an entry to stdout as it is, so it can be piped on machines without
+// a clipboard. anything else the daemon says goes to stderr
+fn paste_to_stdout(socket: &str, offset: Option<usize>, json: bool) {
+    let written = match ask(socket, Request::PasteRaw { offset }) {
+        Ok(Reply::Output { data, .. }) if json => {
+            println!("{}", data);
+            Ok(())
         }
-        Err(_) => {
-            eprintln!("daemon is not running");
+        Ok(Reply::Output { text, data }) => match data["png"].as_str() {
+            Some(png) => STANDARD
+                .decode(png)
+                .map_err(|e| format!("failed to decode image: {}", e))
+                .and_then(|png| write_stdout(&png)),
+            None => write_stdout(text.as_bytes()),
+        },
+        Ok(Reply::Message { text } | Reply::Error { text }) => Err(text),
+        Err((code, e)) => fail(&e, code, json),
+    };
+    if let Err(e) = written {
+        fail(&e, EXIT_FAILED, json);
+    }
+}
+
+fn write_stdout(bytes: &[u8]) -> Result<(), String> {
+    let mut stdout = std::io::stdout().lock();
+    stdout
+        .write_all(bytes)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| format!("failed to write to stdout: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_reads_units() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("90s"), Ok(90));
+        assert_eq!(parse_duration("5m"), Ok(5 * 60));
+        assert_eq!(parse_duration("1h"), Ok(60 * 60));
+        assert_eq!(parse_duration("2d"), Ok(2 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn parse_duration_refuses_the_rest() {
+        for duration in ["", "m", "0", "0h", "5w", "1.5h", "-1m", "5 m", "1h30m"] {
+            assert!(parse_duration(duration).is_err(), "{:?}", duration);
         }
+        assert!(parse_duration(&format!("{}d", u64::MAX)).is_err());
     }
 }
@@ -0,0 +1,97 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// counters behind /metrics, shared by the control plane and the http server.
+// they start over with the daemon, which prometheus copes with. /stats and
+// /metrics read from here rather than ask the control plane, which may be
+// busy with a whole anti entropy round
+pub struct Metrics {
+    pub gossip_sent: AtomicU64,
+    pub gossip_received: AtomicU64,
+    pub anti_entropy_rounds: AtomicU64,
+    pub entries_pulled: AtomicU64,
+    // peers currently known, set by the control plane whenever it reloads them
+    pub neighbors: AtomicU64,
+    started_at: Instant,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            gossip_sent: AtomicU64::new(0),
+            gossip_received: AtomicU64::new(0),
+            anti_entropy_rounds: AtomicU64::new(0),
+            entries_pulled: AtomicU64::new(0),
+            neighbors: AtomicU64::new(0),
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn neighbors(&self) -> u64 {
+        self.neighbors.load(Ordering::Relaxed)
+    }
+
+    pub fn set(gauge: &AtomicU64, n: u64) {
+        gauge.store(n, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // the prometheus text format, with the gauges read at scrape time
+    pub fn render(&self, entries: u64, files: u64) -> String {
+        let counters = [
+            (
+                "slate_gossip_sent_total",
+                "entries gossiped to peers",
+                &self.gossip_sent,
+            ),
+            (
+                "slate_gossip_received_total",
+                "entries gossiped to us by peers",
+                &self.gossip_received,
+            ),
+            (
+                "slate_anti_entropy_rounds_total",
+                "anti entropy rounds run",
+                &self.anti_entropy_rounds,
+            ),
+            (
+                "slate_entries_pulled_total",
+                "entries pulled from peers by anti entropy",
+                &self.entries_pulled,
+            ),
+        ];
+        let gauges = [
+            ("slate_neighbors", "peers currently known", self.neighbors()),
+            (
+                "slate_clipboard_entries",
+                "clipboard entries stored",
+                entries,
+            ),
+            ("slate_files", "files stored", files),
+        ];
+
+        let mut out = String::new();
+        for (name, help, counter) in counters {
+            let value = counter.load(Ordering::Relaxed);
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n"
+            );
+        }
+        for (name, help, value) in gauges {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+            );
+        }
+        out
+    }
+}
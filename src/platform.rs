@@ -0,0 +1,122 @@
+use std::path::PathBuf;
+
+// which side of starting the daemon we ended up on
+pub enum Spawned {
+    // the cli, which can report back and exit
+    Parent,
+    // the daemon itself, still has to be run. windows starts it through the
+    // hidden `daemon` command instead
+    #[cfg_attr(windows, allow(dead_code))]
+    Child,
+}
+
+// where the pid file, log and database live by default
+pub fn runtime_path(name: &str) -> PathBuf {
+    if cfg!(unix) {
+        PathBuf::from("/tmp").join(name)
+    } else {
+        std::env::temp_dir().join(name)
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{spawn_daemon, terminate};
+#[cfg(windows)]
+pub use windows::{spawn_daemon, terminate};
+
+#[cfg(unix)]
+mod unix {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::{fs, os::fd::AsRawFd, path::Path};
+
+    use super::Spawned;
+
+    // the usual double fork: the first child starts a new session, so the
+    // daemon has no controlling terminal and survives it closing, then forks
+    // again so the daemon is not a session leader and can never acquire one
+    pub fn spawn_daemon(log_path: &Path) -> Result<Spawned, String> {
+        match unsafe { libc::fork() } {
+            -1 => return Err("failed to fork process to start daemon".to_string()),
+            0 => {}
+            child => {
+                // the first child exits right after forking again
+                unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+                return Ok(Spawned::Parent);
+            }
+        }
+
+        unsafe {
+            libc::setsid();
+            match libc::fork() {
+                -1 => libc::_exit(1),
+                0 => {}
+                _ => libc::_exit(0),
+            }
+            // do not keep whatever directory we were started in busy
+            libc::chdir(c"/".as_ptr());
+        }
+
+        // output prints to a log file, easy to debug. only we may read it,
+        // also if an older version created it
+        let log_file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(log_path)
+            .map_err(|e| format!("failed to open log file: {}", e))?;
+        fs::set_permissions(log_path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("failed to restrict log file: {}", e))?;
+        let null = fs::File::open("/dev/null").map_err(|e| e.to_string())?;
+        unsafe {
+            libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+            libc::dup2(log_file.as_raw_fd(), libc::STDOUT_FILENO);
+            libc::dup2(log_file.as_raw_fd(), libc::STDERR_FILENO);
+        }
+        Ok(Spawned::Child)
+    }
+
+    pub fn terminate(pid: u32) {
+        unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::os::windows::process::CommandExt;
+    use std::process::{Command, Stdio};
+    use std::{fs, path::Path};
+
+    use super::Spawned;
+
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+    // there is no fork, so run ourselves again in the background with the
+    // hidden `daemon` command, writing into the log file
+    pub fn spawn_daemon(log_path: &Path) -> Result<Spawned, String> {
+        let log_file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .map_err(|e| format!("failed to open log file: {}", e))?;
+        let stderr = log_file.try_clone().map_err(|e| e.to_string())?;
+        let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+
+        Command::new(exe)
+            .arg("daemon")
+            .stdin(Stdio::null())
+            .stdout(log_file)
+            .stderr(stderr)
+            .creation_flags(DETACHED_PROCESS | CREATE_NO_WINDOW)
+            .spawn()
+            .map_err(|e| format!("failed to start daemon: {}", e))?;
+        Ok(Spawned::Parent)
+    }
+
+    pub fn terminate(pid: u32) {
+        let _ = Command::new("taskkill")
+            .args(["/PID", &pid.to_string(), "/F"])
+            .stdout(Stdio::null())
+            .status();
+    }
+}
@@ -0,0 +1,141 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// frames on the daemon's socket, one request and one reply per connection:
+//
+//   length  u32, big endian, of the payload alone
+//   kind    u8, REQUEST or REPLY
+//   payload json of an ipc::Request or ipc::Reply
+//
+// the payload is never scanned for a delimiter, so it can hold anything,
+// binary included. a daemon still answers a request that starts with `{`
+// as the single line of json older clis send, with a line of json back
+
+pub const REQUEST: u8 = b'q';
+pub const REPLY: u8 = b'r';
+
+const HEADER_LEN: usize = 5;
+// far more than a clipboard entry, a corrupt length is caught before
+// anything is allocated for it
+const MAX_PAYLOAD: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub kind: u8,
+    pub payload: Vec<u8>,
+}
+
+pub fn encode(kind: u8, payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() <= MAX_PAYLOAD, "frame payload too large");
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// the frame at the start of buf and how many bytes it took, None while buf
+// does not hold all of it yet
+pub fn decode(buf: &[u8]) -> Result<Option<(Frame, usize)>, String> {
+    let Some(header) = buf.get(..HEADER_LEN) else {
+        return Ok(None);
+    };
+    let (len, kind) = parse_header(header)?;
+    let Some(payload) = buf.get(HEADER_LEN..HEADER_LEN + len) else {
+        return Ok(None);
+    };
+    let frame = Frame {
+        kind,
+        payload: payload.to_vec(),
+    };
+    Ok(Some((frame, HEADER_LEN + len)))
+}
+
+pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame, String> {
+    let mut header = [0u8; HEADER_LEN];
+    reader
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| format!("failed to read frame: {}", e))?;
+    let (len, kind) = parse_header(&header)?;
+    let mut payload = vec![0u8; len];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(|e| format!("failed to read frame: {}", e))?;
+    Ok(Frame { kind, payload })
+}
+
+fn parse_header(header: &[u8]) -> Result<(usize, u8), String> {
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(format!("frame of {} bytes is too large", len));
+    }
+    match header[4] {
+        kind @ (REQUEST | REPLY) => Ok((len, kind)),
+        kind => Err(format!("unknown frame kind {}", kind)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let bytes = encode(REQUEST, b"{\"command\":\"status\"}");
+        let (frame, used) = decode(&bytes).unwrap().unwrap();
+        assert_eq!(used, bytes.len());
+        assert_eq!(frame.kind, REQUEST);
+        assert_eq!(frame.payload, b"{\"command\":\"status\"}");
+    }
+
+    #[test]
+    fn binary_and_empty_payloads() {
+        let binary = [0u8, b'\n', 0xff, b'\r', 0];
+        let (frame, _) = decode(&encode(REPLY, &binary)).unwrap().unwrap();
+        assert_eq!(frame.payload, binary);
+        let (frame, used) = decode(&encode(REPLY, b"")).unwrap().unwrap();
+        assert!(frame.payload.is_empty());
+        assert_eq!(used, HEADER_LEN);
+    }
+
+    #[test]
+    fn incomplete_frames_wait_for_more() {
+        let bytes = encode(REQUEST, b"hello");
+        for end in 0..bytes.len() {
+            assert_eq!(decode(&bytes[..end]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn frames_back_to_back() {
+        let mut bytes = encode(REQUEST, b"one");
+        bytes.extend(encode(REPLY, b"two"));
+        let (first, used) = decode(&bytes).unwrap().unwrap();
+        let (second, _) = decode(&bytes[used..]).unwrap().unwrap();
+        assert_eq!(first.payload, b"one");
+        assert_eq!(second.kind, REPLY);
+        assert_eq!(second.payload, b"two");
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut unknown = encode(REQUEST, b"x");
+        unknown[4] = b'z';
+        assert!(decode(&unknown).is_err());
+
+        let mut huge = (MAX_PAYLOAD as u32 + 1).to_be_bytes().to_vec();
+        huge.push(REQUEST);
+        assert!(decode(&huge).is_err());
+    }
+
+    #[test]
+    fn read_matches_decode() {
+        let bytes = encode(REPLY, b"{\"kind\":\"message\",\"text\":\"ok\"}");
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let frame = rt.block_on(read(&mut &bytes[..])).unwrap();
+        assert_eq!(Some((frame, bytes.len())), decode(&bytes).unwrap());
+    }
+}